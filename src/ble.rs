@@ -1,52 +1,709 @@
-use anyhow::Result;
-use hciraw::HciSocket;
-use std::time::Duration;
-use tokio::time::sleep;
+use anyhow::{Context, Result, bail};
+use hciraw::{HciChannel, HciSocket, HciSocketAddr};
+use std::{
+    io, mem,
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::block_in_place,
+    time::{error::Elapsed, sleep, timeout},
+};
 
-use crate::fan::WrappedPacket;
+use tracing::{Instrument, Span};
+
+use crate::{config::Config, fan::WrappedPacket};
+
+pub mod scan;
 
 const HCI_COMMAND_PKT: u8 = 0x01;
+const HCI_EVENT_PKT: u8 = 0x04;
+const OGF_HOST_CTL: u16 = 0x03;
+const OGF_INFO_PARAM: u16 = 0x04;
 const OGF_LE_CTL: u16 = 0x08;
 
+const OCF_READ_LOCAL_NAME: u16 = 0x0014;
+const OCF_READ_LOCAL_VERSION: u16 = 0x0001;
+const OCF_READ_BD_ADDR: u16 = 0x0009;
+
+const EVT_CMD_COMPLETE: u8 = 0x0E;
+const EVT_CMD_STATUS: u8 = 0x0F;
+
+// the events every socket needs to see the answers to its commands
+const COMMAND_EVENTS: [u32; 2] = [(1 << EVT_CMD_COMPLETE) | (1 << EVT_CMD_STATUS), 0];
+
+const HCI_SUCCESS: u8 = 0x00;
+const HCI_COMMAND_DISALLOWED: u8 = 0x0C;
+
+// from linux/include/net/bluetooth/hci_sock.h
+const SOL_HCI: libc::c_int = 0;
+const HCI_FILTER: libc::c_int = 2;
+
+const HCI_EVENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+const OCF_LE_READ_LOCAL_SUPPORTED_FEATURES: u16 = 0x03;
+const OCF_LE_SET_RANDOM_ADDRESS: u16 = 0x05;
 const OCF_LE_SET_ADVERTISING_PARAMETERS: u16 = 0x06;
+const OCF_LE_READ_ADVERTISING_TX_POWER: u16 = 0x07;
 const OCF_LE_SET_ADVERTISING_DATA: u16 = 0x08;
 const OCF_LE_SET_ADVERTISE_ENABLE: u16 = 0x0A;
 
-fn create_hci_command(cmd_code: u16, data: &[u8]) -> Vec<u8> {
+const MAX_ADVERTISING_DATA_LEN: usize = 31;
+const _: () = assert!(size_of::<WrappedPacket>() <= MAX_ADVERTISING_DATA_LEN);
+
+const TX_REPEAT_GAP: Duration = Duration::from_millis(50);
+
+// the adapter can take a moment to come back after a reset
+const REBIND_ATTEMPTS: u32 = 3;
+const REBIND_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+#[repr(C)]
+struct HciFilter {
+    type_mask: u32,
+    event_mask: [u32; 2],
+    opcode: u16,
+}
+
+fn opcode(ogf: u16, ocf: u16) -> u16 {
+    ocf | (ogf << 10)
+}
+
+fn le_opcode(cmd_code: u16) -> u16 {
+    opcode(OGF_LE_CTL, cmd_code)
+}
+
+fn create_hci_command(opcode: u16, data: &[u8]) -> Vec<u8> {
     let mut buf = Vec::with_capacity(data.len() + 4);
 
     buf.push(HCI_COMMAND_PKT);
-    buf.extend((cmd_code + (OGF_LE_CTL << 10)).to_ne_bytes());
+    buf.extend(opcode.to_le_bytes());
+    debug_assert!(data.len() <= u8::MAX as usize, "HCI command data too long");
     buf.push(data.len() as u8);
     buf.extend_from_slice(data);
 
     buf
 }
 
-fn generate_advertising_params() -> [u8; 15] {
-    [32, 0, 32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x7, 0]
+// intervals are in units of 0.625ms
+fn generate_advertising_params(
+    interval_min: u16,
+    interval_max: u16,
+    random_address: bool,
+    channel_map: u8,
+) -> [u8; 15] {
+    let mut params = [32, 0, 32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x7, 0];
+
+    params[0..2].copy_from_slice(&interval_min.to_le_bytes());
+    params[2..4].copy_from_slice(&interval_max.to_le_bytes());
+    params[5] = random_address as u8; // own address type
+    params[13] = channel_map;
+
+    params
+}
+
+// a static random address has the two most significant bits set, and the rest of it
+// may not be all zeros or all ones
+fn generate_random_static_address() -> [u8; 6] {
+    loop {
+        let mut address: [u8; 6] = rand::random();
+        address[5] |= 0xC0;
+
+        let mut rest = address;
+        rest[5] &= 0x3F;
+        if rest != [0; 6] && rest != [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x3F] {
+            return address;
+        }
+    }
+}
+
+fn bind_hci_socket(hci_device: u16) -> Result<HciSocket> {
+    bind_hci_socket_with_events(hci_device, COMMAND_EVENTS)
+}
+
+fn bind_hci_socket_with_events(hci_device: u16, event_mask: [u32; 2]) -> Result<HciSocket> {
+    let socket = HciSocket::bind(HciSocketAddr::new(Some(hci_device), HciChannel::Raw))
+        .with_context(|| format!("failed to bind to hci{hci_device}"))?;
+
+    configure_event_socket(&socket, event_mask)
+        .with_context(|| format!("failed to configure hci{hci_device} for reading events"))?;
+
+    Ok(socket)
+}
+
+// raw sockets filter out every event by default, and reads should not block forever
+fn configure_event_socket(socket: &HciSocket, event_mask: [u32; 2]) -> Result<()> {
+    let filter = HciFilter {
+        type_mask: 1 << HCI_EVENT_PKT,
+        event_mask,
+        opcode: 0,
+    };
+    let timeout = libc::timeval {
+        tv_sec: HCI_EVENT_TIMEOUT.as_secs() as libc::time_t,
+        tv_usec: HCI_EVENT_TIMEOUT.subsec_micros() as libc::suseconds_t,
+    };
+
+    let fd = socket.as_raw_fd();
+    // SAFETY: both options are passed pointers to correctly sized, initialized values
+    unsafe {
+        if libc::setsockopt(
+            fd,
+            SOL_HCI,
+            HCI_FILTER,
+            (&raw const filter).cast(),
+            mem::size_of::<HciFilter>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error()).context("failed to set HCI event filter");
+        }
+        if libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            (&raw const timeout).cast(),
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error()).context("failed to set HCI read timeout");
+        }
+    }
+
+    Ok(())
+}
+
+// sends a command and waits for the controller to answer it, returning the status byte
+// and whatever else the command returns
+fn send_raw_command(hci_socket: &HciSocket, opcode: u16, data: &[u8]) -> Result<(u8, Vec<u8>)> {
+    hci_socket.send(&create_hci_command(opcode, data))?;
+
+    let (status, params) = block_in_place(|| wait_for_command_status(hci_socket, opcode))?;
+    log::debug!("HCI command {opcode:#06x} completed with status {status:#04x}");
+
+    Ok((status, params))
+}
+
+// where the LE commands go, so the advertising sequence can run against something other
+// than a real adapter
+trait LeCommands {
+    // sends the command and returns the status the controller answered with
+    fn send_le_command(&self, cmd_code: u16, data: &[u8]) -> Result<u8>;
+}
+
+impl LeCommands for HciSocket {
+    fn send_le_command(&self, cmd_code: u16, data: &[u8]) -> Result<u8> {
+        let (status, _) = send_raw_command(self, le_opcode(cmd_code), data)?;
+        Ok(status)
+    }
+}
+
+fn send_command(hci_socket: &impl LeCommands, cmd_code: u16, data: &[u8]) -> Result<()> {
+    match hci_socket.send_le_command(cmd_code, data)? {
+        HCI_SUCCESS => Ok(()),
+        status => bail!(
+            "HCI command {:#06x} failed with status {status:#04x}",
+            le_opcode(cmd_code)
+        ),
+    }
+}
+
+fn query(hci_socket: &HciSocket, opcode: u16) -> Result<Vec<u8>> {
+    match send_raw_command(hci_socket, opcode, &[])? {
+        (HCI_SUCCESS, params) => Ok(params),
+        (status, _) => bail!("HCI command {opcode:#06x} failed with status {status:#04x}"),
+    }
+}
+
+// Makes sure the configured index is actually an adapter that answers, and logs which one
+// it is so it's obvious when the wrong one was picked on a host with several.
+fn log_adapter_info(hci_device: u16, hci_socket: &HciSocket) -> Result<()> {
+    let address = query(hci_socket, opcode(OGF_INFO_PARAM, OCF_READ_BD_ADDR))
+        .context("failed to read adapter address")?;
+    let [a, b, c, d, e, f] = address[..] else {
+        bail!("adapter returned a malformed address");
+    };
+
+    let name = query(hci_socket, opcode(OGF_HOST_CTL, OCF_READ_LOCAL_NAME))
+        .context("failed to read adapter name")?;
+    let name = name.split(|&byte| byte == 0).next().unwrap_or_default();
+
+    log::info!(
+        "using hci{hci_device} ({f:02X}:{e:02X}:{d:02X}:{c:02X}:{b:02X}:{a:02X}, {:?})",
+        String::from_utf8_lossy(name)
+    );
+
+    // the rest is only for debugging, so failing to read it isn't fatal
+    if let Err(err) = log_adapter_capabilities(hci_device, hci_socket) {
+        log::warn!(
+            "{:?}",
+            err.context(format!("failed to read hci{hci_device} capabilities"))
+        );
+    }
+
+    Ok(())
+}
+
+// There is no HCI command to read back whether advertising is enabled, so this sticks to
+// what the adapter can report about itself.
+fn log_adapter_capabilities(hci_device: u16, hci_socket: &HciSocket) -> Result<()> {
+    let version = query(hci_socket, opcode(OGF_INFO_PARAM, OCF_READ_LOCAL_VERSION))?;
+    let (hci_version, lmp_version, manufacturer) = match version[..] {
+        [hci, _, _, lmp, lo, hi, ..] => (hci, lmp, u16::from_le_bytes([lo, hi])),
+        _ => bail!("adapter returned malformed version information"),
+    };
+
+    let features = query(hci_socket, le_opcode(OCF_LE_READ_LOCAL_SUPPORTED_FEATURES))?;
+    let features: [u8; 8] = features[..]
+        .try_into()
+        .context("adapter returned malformed LE features")?;
+
+    let tx_power = query(hci_socket, le_opcode(OCF_LE_READ_ADVERTISING_TX_POWER))?;
+    let [tx_power] = tx_power[..] else {
+        bail!("adapter returned malformed advertising TX power");
+    };
+
+    log::info!(
+        "hci{hci_device}: HCI version {hci_version:#04x}, LMP version {lmp_version:#04x}, \
+        manufacturer {manufacturer:#06x}"
+    );
+    log::info!(
+        "hci{hci_device}: LE features {:#018x}, advertising TX power {} dBm",
+        u64::from_le_bytes(features),
+        tx_power as i8
+    );
+
+    Ok(())
+}
+
+// older controllers refuse to disable advertising when it is already disabled
+fn send_disable_advertising(hci_socket: &impl LeCommands) -> Result<()> {
+    match hci_socket.send_le_command(OCF_LE_SET_ADVERTISE_ENABLE, &[0])? {
+        HCI_SUCCESS | HCI_COMMAND_DISALLOWED => Ok(()),
+        status => bail!("failed to disable advertising, status {status:#04x}"),
+    }
+}
+
+fn wait_for_command_status(hci_socket: &HciSocket, opcode: u16) -> Result<(u8, Vec<u8>)> {
+    let deadline = Instant::now() + HCI_EVENT_TIMEOUT;
+    let mut buf = [0u8; 260];
+
+    // the socket also sees events for commands sent by anything else on this adapter
+    while Instant::now() < deadline {
+        // SAFETY: buf is valid for writes of its whole length
+        let len = unsafe {
+            libc::recv(
+                hci_socket.as_raw_fd(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => break,
+                io::ErrorKind::Interrupted => continue,
+                _ => return Err(err).context("failed to read HCI event"),
+            }
+        }
+
+        let event = &buf[..len as usize];
+        if event.len() < 3 || event[0] != HCI_EVENT_PKT {
+            continue;
+        }
+
+        // [type, event code, param len, ...params]
+        let (event_opcode, status, params) = match (event[1], &event[3..]) {
+            (EVT_CMD_COMPLETE, [_, lo, hi, status, params @ ..]) => {
+                (u16::from_le_bytes([*lo, *hi]), *status, params)
+            }
+            (EVT_CMD_STATUS, [status, _, lo, hi, ..]) => {
+                (u16::from_le_bytes([*lo, *hi]), *status, &[][..])
+            }
+            _ => continue,
+        };
+
+        if event_opcode == opcode {
+            return Ok((status, params.to_vec()));
+        }
+    }
+
+    bail!("timed out waiting for HCI command {opcode:#06x} to complete")
+}
+
+enum BleRequest {
+    // each packet is advertised for its duration, one after another
+    Advertise(Vec<(WrappedPacket, Duration)>),
+    DisableAdvertising,
+}
+
+struct BleJob {
+    request: BleRequest,
+    // the job is logged under the span it was submitted from, like the fan it's for
+    span: Span,
+    // sent when the adapter gets to the job, which may be a while behind other fans' jobs
+    started: oneshot::Sender<()>,
+    done: oneshot::Sender<Result<()>>,
+}
+
+// Owns the socket and handles one job at a time, since the controller has to process
+// the advertising commands serially and overlapping sequences clog up the socket.
+// Cheap USB dongles sometimes get reset by the kernel, after which every send on the
+// old socket fails, so the socket is re-bound after enough failures in a row.
+struct AdapterTask {
+    hci_device: u16,
+    reopen_after_failures: u32,
+    settings: AdvertiseSettings,
+    socket: HciSocket,
+    consecutive_failures: u32,
+}
+
+struct AdvertiseSettings {
+    tx_repeat: u8,
+    adv_interval_min: u16,
+    adv_interval_max: u16,
+    randomize_adv_address: bool,
+    adv_channel_map: u8,
+}
+
+impl AdvertiseSettings {
+    fn new(config: &Config) -> Self {
+        Self {
+            tx_repeat: config.tx_repeat,
+            adv_interval_min: config.adv_interval_min,
+            adv_interval_max: config.adv_interval_max,
+            randomize_adv_address: config.randomize_adv_address,
+            adv_channel_map: config.adv_channel_map,
+        }
+    }
+}
+
+impl AdapterTask {
+    async fn run(mut self, mut jobs: mpsc::UnboundedReceiver<BleJob>) {
+        while let Some(job) = jobs.recv().await {
+            // whoever submitted it stopped waiting while it was queued, and may already have
+            // queued a retry, so sending it now would only repeat the packets
+            if job.done.is_closed() {
+                let _enter = job.span.enter();
+                log::debug!("dropping BLE job that is no longer waited on");
+                continue;
+            }
+            let _ = job.started.send(());
+
+            let result = async {
+                match job.request {
+                    BleRequest::Advertise(packets) => self.advertise(&packets).await,
+                    BleRequest::DisableAdvertising => send_disable_advertising(&self.socket),
+                }
+            }
+            .instrument(job.span)
+            .await;
+
+            // the caller may have stopped waiting, which is fine
+            let _ = job.done.send(result);
+        }
+    }
+    async fn advertise(&mut self, packets: &[(WrappedPacket, Duration)]) -> Result<()> {
+        let Err(err) = advertise_on(&self.socket, &self.settings, packets).await else {
+            self.consecutive_failures = 0;
+            return Ok(());
+        };
+
+        self.consecutive_failures += 1;
+        if self.reopen_after_failures == 0 || self.consecutive_failures < self.reopen_after_failures
+        {
+            return Err(err);
+        }
+
+        log::warn!(
+            "{:?}",
+            err.context(format!(
+                "{} consecutive HCI failures, reopening hci{}",
+                self.consecutive_failures, self.hci_device
+            ))
+        );
+
+        let mut backoff = REBIND_INITIAL_BACKOFF;
+        for attempt in 1..=REBIND_ATTEMPTS {
+            if attempt != 1 {
+                sleep(backoff).await;
+                backoff *= 2;
+            }
+
+            let result = match bind_hci_socket(self.hci_device) {
+                Ok(socket) => {
+                    self.socket = socket;
+                    advertise_on(&self.socket, &self.settings, packets).await
+                }
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(()) => {
+                    self.consecutive_failures = 0;
+                    return Ok(());
+                }
+                Err(err) => {
+                    log::warn!(
+                        "{:?}",
+                        err.context(format!(
+                            "attempt {attempt}/{REBIND_ATTEMPTS} to recover hci{} failed",
+                            self.hci_device
+                        ))
+                    );
+                }
+            }
+        }
+
+        bail!(
+            "hci{} did not recover after {REBIND_ATTEMPTS} attempts",
+            self.hci_device
+        )
+    }
 }
 
-// this whole thing sucks because it requires commands to be processed serially
-// and can clog up the socket if commands are sent quickly
-pub async fn advertise_ble_message(hci_socket: &HciSocket, data: &WrappedPacket) -> Result<()> {
-    let mut buf: Vec<u8> = Vec::from(&data.0);
-    buf.insert(0, data.0.len() as u8);
+// this whole thing sucks because it requires commands to be processed serially,
+// so each command waits for the controller to answer before the next one is sent
+async fn advertise_on(
+    hci_socket: &impl LeCommands,
+    settings: &AdvertiseSettings,
+    packets: &[(WrappedPacket, Duration)],
+) -> Result<()> {
+    let mut bufs = Vec::with_capacity(packets.len());
+    for (data, duration) in packets {
+        // adapters reject anything longer, often without saying why
+        if data.0.len() > MAX_ADVERTISING_DATA_LEN {
+            bail!(
+                "advertising data is {} bytes, over the legacy limit of {MAX_ADVERTISING_DATA_LEN}",
+                data.0.len()
+            );
+        }
+
+        let mut buf: Vec<u8> = Vec::from(&data.0);
+        buf.insert(0, data.0.len() as u8);
+        bufs.push((buf, *duration));
+    }
 
-    hci_socket.send(&create_hci_command(OCF_LE_SET_ADVERTISE_ENABLE, &[0]))?;
+    send_disable_advertising(hci_socket)?;
 
-    hci_socket.send(&create_hci_command(
+    // some fans ignore repeated commands coming from the same address
+    if settings.randomize_adv_address {
+        send_command(
+            hci_socket,
+            OCF_LE_SET_RANDOM_ADDRESS,
+            &generate_random_static_address(),
+        )
+        .context("failed to set random address")?;
+    }
+
+    send_command(
+        hci_socket,
         OCF_LE_SET_ADVERTISING_PARAMETERS,
-        &generate_advertising_params(),
-    ))?;
+        &generate_advertising_params(
+            settings.adv_interval_min,
+            settings.adv_interval_max,
+            settings.randomize_adv_address,
+            settings.adv_channel_map,
+        ),
+    )
+    .context("failed to set advertising parameters")?;
+
+    // the repeats carry the exact same packet (and tx_count), so the fan dedupes them
+    for i in 0..settings.tx_repeat.max(1) {
+        if i != 0 {
+            sleep(TX_REPEAT_GAP).await;
+        }
 
-    hci_socket.send(&create_hci_command(OCF_LE_SET_ADVERTISING_DATA, &buf))?;
+        // the data can be changed while advertising, so the packets follow each other
+        // without turning advertising off in between
+        for (j, (buf, duration)) in bufs.iter().enumerate() {
+            send_command(hci_socket, OCF_LE_SET_ADVERTISING_DATA, buf)
+                .context("failed to set advertising data")?;
 
-    hci_socket.send(&create_hci_command(OCF_LE_SET_ADVERTISE_ENABLE, &[1]))?;
+            if j == 0 {
+                send_command(hci_socket, OCF_LE_SET_ADVERTISE_ENABLE, &[1])
+                    .context("failed to enable advertising")?;
+            }
 
-    sleep(Duration::from_millis(500)).await;
+            sleep(*duration).await;
+        }
 
-    hci_socket.send(&create_hci_command(OCF_LE_SET_ADVERTISE_ENABLE, &[0]))?;
+        send_disable_advertising(hci_socket)?;
+    }
 
     Ok(())
 }
+
+pub struct BleAdapter {
+    // None in dry run mode, where nothing is ever sent
+    jobs: Option<mpsc::UnboundedSender<BleJob>>,
+}
+
+impl BleAdapter {
+    pub fn open(hci_device: u16, config: &Config) -> Result<Self> {
+        if config.dry_run {
+            return Ok(Self { jobs: None });
+        }
+
+        let socket = bind_hci_socket(hci_device)?;
+        log_adapter_info(hci_device, &socket).with_context(|| {
+            format!("hci{hci_device} is not responding, check hci_device and that it is up")
+        })?;
+
+        let task = AdapterTask {
+            hci_device,
+            reopen_after_failures: config.hci_reopen_after_failures,
+            settings: AdvertiseSettings::new(config),
+            socket,
+            consecutive_failures: 0,
+        };
+
+        let (jobs_tx, jobs_rx) = mpsc::unbounded_channel();
+        tokio::spawn(task.run(jobs_rx));
+
+        Ok(Self {
+            jobs: Some(jobs_tx),
+        })
+    }
+    // Queues the job and waits for the adapter to get to it, then hands back where its result
+    // will arrive. None in dry run mode, where there is nothing to wait for.
+    async fn start(&self, request: BleRequest) -> Result<Option<oneshot::Receiver<Result<()>>>> {
+        let Some(jobs) = &self.jobs else {
+            return Ok(None);
+        };
+
+        let (started_tx, started_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+        jobs.send(BleJob {
+            request,
+            span: Span::current(),
+            started: started_tx,
+            done: done_tx,
+        })
+        .ok()
+        .context("BLE task has stopped")?;
+
+        started_rx.await.context("BLE task has stopped")?;
+        Ok(Some(done_rx))
+    }
+    async fn submit(&self, request: BleRequest) -> Result<()> {
+        match self.start(request).await? {
+            Some(done_rx) => done_rx.await.context("BLE task has stopped")?,
+            None => Ok(()),
+        }
+    }
+    pub async fn advertise(
+        &self,
+        data: &WrappedPacket,
+        duration: Duration,
+        send_timeout: Duration,
+    ) -> Result<Result<()>, Elapsed> {
+        self.advertise_batch(&[(WrappedPacket(data.0), duration)], send_timeout)
+            .await
+    }
+    // Advertises the packets back to back within a single advertising window. The timeout
+    // only starts once the adapter gets to them, so waiting behind other fans doesn't count.
+    pub async fn advertise_batch(
+        &self,
+        packets: &[(WrappedPacket, Duration)],
+        send_timeout: Duration,
+    ) -> Result<Result<()>, Elapsed> {
+        let request = BleRequest::Advertise(
+            packets
+                .iter()
+                .map(|(data, duration)| (WrappedPacket(data.0), *duration))
+                .collect(),
+        );
+        let done_rx = match self.start(request).await {
+            Ok(Some(done_rx)) => done_rx,
+            Ok(None) => return Ok(Ok(())),
+            Err(err) => return Ok(Err(err)),
+        };
+
+        timeout(send_timeout, done_rx).await.map(|done| {
+            done.context("BLE task has stopped")
+                .and_then(|result| result)
+        })
+    }
+    pub async fn disable_advertising(&self) -> Result<()> {
+        self.submit(BleRequest::DisableAdvertising).await
+    }
+}
+
+// Stands in for the adapter in tests. It takes jobs off the same channel as AdapterTask and
+// runs the same advertising sequence, but keeps every command instead of sending it.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct FakeController {
+    commands: std::sync::Mutex<Vec<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl FakeController {
+    pub(crate) fn take_commands(&self) -> Vec<Vec<u8>> {
+        mem::take(&mut self.commands.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+impl LeCommands for FakeController {
+    fn send_le_command(&self, cmd_code: u16, data: &[u8]) -> Result<u8> {
+        self.commands
+            .lock()
+            .unwrap()
+            .push(create_hci_command(le_opcode(cmd_code), data));
+        Ok(HCI_SUCCESS)
+    }
+}
+
+#[cfg(test)]
+impl BleAdapter {
+    pub(crate) fn fake(config: &Config) -> (Self, std::sync::Arc<FakeController>) {
+        let controller = std::sync::Arc::new(FakeController::default());
+        let settings = AdvertiseSettings::new(config);
+
+        let (jobs_tx, mut jobs_rx) = mpsc::unbounded_channel::<BleJob>();
+        let fake = controller.clone();
+        tokio::spawn(async move {
+            while let Some(job) = jobs_rx.recv().await {
+                let _ = job.started.send(());
+                let result = match job.request {
+                    BleRequest::Advertise(packets) => {
+                        advertise_on(&*fake, &settings, &packets).await
+                    }
+                    BleRequest::DisableAdvertising => send_disable_advertising(&*fake),
+                };
+                let _ = job.done.send(result);
+            }
+        });
+
+        (
+            Self {
+                jobs: Some(jobs_tx),
+            },
+            controller,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_opcode_is_little_endian() {
+        // OGF 0x08, OCF 0x0008 packs to 0x2008
+        assert_eq!(
+            create_hci_command(le_opcode(OCF_LE_SET_ADVERTISING_DATA), &[0xAA, 0xBB]),
+            [HCI_COMMAND_PKT, 0x08, 0x20, 2, 0xAA, 0xBB]
+        );
+    }
+
+    #[test]
+    fn opcode_packs_ogf_above_ocf() {
+        // OGF 0x04, OCF 0x0001 packs to 0x1001
+        assert_eq!(
+            create_hci_command(opcode(OGF_INFO_PARAM, OCF_READ_LOCAL_VERSION), &[]),
+            [HCI_COMMAND_PKT, 0x01, 0x10, 0]
+        );
+    }
+}
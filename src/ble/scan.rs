@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use hciraw::HciSocket;
+use std::{io, os::fd::AsRawFd};
+use tokio::{sync::mpsc, task};
+
+use super::{COMMAND_EVENTS, HCI_EVENT_PKT, bind_hci_socket_with_events, send_command};
+use crate::fan::{ENCRYPTED_PACKET_LEN, EncryptedPacket, PacketData, decrypt};
+
+const EVT_LE_META_EVENT: u8 = 0x3E;
+const EVT_LE_ADVERTISING_REPORT: u8 = 0x02;
+
+const OCF_LE_SET_SCAN_PARAMETERS: u16 = 0x0B;
+const OCF_LE_SET_SCAN_ENABLE: u16 = 0x0C;
+
+// the packets are sent as a "complete list of 16-bit service UUIDs"
+const AD_TYPE_UUID16_COMPLETE: u8 = 0x03;
+
+// Listens for packets sent to the fans by anything else, like their physical remotes, so
+// the cached state can follow along. The fans themselves don't seem to advertise anything.
+// Scanning is passive, so this never sends anything over the air.
+pub async fn start(hci_device: u16) -> Result<mpsc::UnboundedReceiver<PacketData>> {
+    let socket = bind_hci_socket_with_events(
+        hci_device,
+        [
+            COMMAND_EVENTS[0],
+            COMMAND_EVENTS[1] | 1 << (EVT_LE_META_EVENT - 32),
+        ],
+    )?;
+
+    // passive, with a 10ms interval and window so nothing is missed
+    let mut params = [0u8; 7];
+    params[1..3].copy_from_slice(&0x10u16.to_le_bytes());
+    params[3..5].copy_from_slice(&0x10u16.to_le_bytes());
+    send_command(&socket, OCF_LE_SET_SCAN_PARAMETERS, &params)
+        .with_context(|| format!("failed to set scan parameters on hci{hci_device}"))?;
+
+    // some controllers filter duplicates by address alone, which would drop every packet
+    // from a remote after its first
+    send_command(&socket, OCF_LE_SET_SCAN_ENABLE, &[1, 0])
+        .with_context(|| format!("failed to enable scanning on hci{hci_device}"))?;
+
+    let (packets_tx, packets_rx) = mpsc::unbounded_channel();
+    task::spawn_blocking(move || {
+        if let Err(err) = read_reports(&socket, &packets_tx) {
+            log::error!(
+                "{:?}",
+                err.context(format!("Scanning on hci{hci_device} has stopped"))
+            );
+        }
+    });
+
+    Ok(packets_rx)
+}
+
+fn read_reports(socket: &HciSocket, packets: &mpsc::UnboundedSender<PacketData>) -> Result<()> {
+    let mut buf = [0u8; 260];
+
+    // the socket has a read timeout, so this notices when nobody is listening anymore
+    while !packets.is_closed() {
+        // SAFETY: buf is valid for writes of its whole length
+        let len = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted => continue,
+                _ => return Err(err).context("failed to read HCI event"),
+            }
+        }
+
+        // [type, event code, param len, subevent, ...reports]
+        let [
+            HCI_EVENT_PKT,
+            EVT_LE_META_EVENT,
+            _,
+            EVT_LE_ADVERTISING_REPORT,
+            reports @ ..,
+        ] = &buf[..len as usize]
+        else {
+            continue;
+        };
+
+        for data in advertising_data(reports) {
+            if let Some(packet) = parse_fan_packet(data) {
+                let _ = packets.send(packet);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// [report count, then for each: event type, address type, address (6), data len, data, rssi]
+fn advertising_data(reports: &[u8]) -> Vec<&[u8]> {
+    let Some((&count, mut rest)) = reports.split_first() else {
+        return Vec::new();
+    };
+
+    let mut data = Vec::new();
+    for _ in 0..count {
+        let Some(&len) = rest.get(8) else {
+            break;
+        };
+        let Some(report_data) = rest.get(9..9 + len as usize) else {
+            break;
+        };
+
+        data.push(report_data);
+        rest = rest.get(10 + len as usize..).unwrap_or_default();
+    }
+
+    data
+}
+
+// advertising data is a list of [len, type, ...value] structures
+fn parse_fan_packet(mut data: &[u8]) -> Option<PacketData> {
+    while let [len, rest @ ..] = data {
+        let len = *len as usize;
+        let structure = rest.get(..len)?;
+
+        if let [AD_TYPE_UUID16_COMPLETE, value @ ..] = structure
+            && let Ok(encrypted) = <[u8; ENCRYPTED_PACKET_LEN]>::try_from(value)
+        {
+            let serialized = decrypt(&EncryptedPacket(encrypted))?;
+            return PacketData::deserialize(&serialized).ok();
+        }
+
+        data = &rest[len..];
+    }
+
+    None
+}
@@ -0,0 +1,229 @@
+use anyhow::{Context, Result, bail};
+use clap::{Parser, ValueEnum, builder::BoolishValueParser};
+use devicectrl_common::{
+    DeviceId,
+    device_types::ceiling_fan::FanDirection,
+    updates::{AttributeUpdate, NumericUpdate},
+};
+use p256::{
+    ecdsa::SigningKey,
+    pkcs8::{EncodePrivateKey, EncodePublicKey},
+};
+use rand_core::OsRng;
+use serde_json::Value;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    iter,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use devicectrl_fan_controller::{
+    ble::BleAdapter,
+    config::{Config, FanConfig},
+    fan::{CachedFanState, coalesce_updates, send_update_to_fan},
+};
+
+const INIT_USAGE: &str = "usage: devicectrl-fan-controller init [<directory>]";
+
+const CONFIG_TEMPLATE: &str = r#"# See the README for all the other options.
+
+# identifies this controller to the server
+device_id = "fan-controller"
+server_addr = "127.0.0.1:8895"
+
+# the server's public key, DER or PEM encoded
+server_public_key_path = "/etc/devicectrl-fan-controller/server_public.der"
+# generated by init, register public_key.der next to it with the server
+private_key_path = {private_key_path}
+
+# the bluetooth adapter to advertise on, 0 for hci0
+hci_device = 0
+
+[[fans]]
+# the id the server sends this fan's commands to
+device_id = "fan"
+# the uid of the remote this controller pretends to be, pair the fan with it like any remote
+remote_uid = 0
+"#;
+
+fn find_fan(config: &Config, device_id: Option<DeviceId>) -> Result<&FanConfig> {
+    match device_id {
+        Some(device_id) => config
+            .fans
+            .iter()
+            .find(|fan| fan.device_id == device_id)
+            .with_context(|| format!("fan {device_id:?} is not configured")),
+        None => match config.fans.as_slice() {
+            [fan] => Ok(fan),
+            _ => bail!("more than one fan is configured, pick one with --fan"),
+        },
+    }
+}
+
+#[derive(Parser)]
+#[command(name = "devicectrl-fan-controller send")]
+struct SendArgs {
+    #[arg(
+        long,
+        help = "device id of the fan, can be left out when only one is configured"
+    )]
+    fan: Option<String>,
+    #[arg(long, value_parser = BoolishValueParser::new(), help = "turn the light on or off")]
+    power: Option<bool>,
+    #[arg(long, help = "brightness to set, within brightness_props")]
+    brightness: Option<u32>,
+    #[arg(
+        long,
+        help = "color temperature to set, in Kelvin if the fan has a Kelvin range"
+    )]
+    color_temp: Option<u32>,
+    #[arg(long, help = "fan speed to set, 0 turns the fan off")]
+    speed: Option<u32>,
+    #[arg(long, value_enum, help = "direction the fan spins in")]
+    direction: Option<DirectionArg>,
+    #[arg(
+        long,
+        help = "packet counter to start from instead of initial_tx_count, each packet sent adds one"
+    )]
+    tx_count: Option<u8>,
+    #[arg(help = "an update in the same JSON representation as updates from the server")]
+    update: Option<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DirectionArg {
+    Forward,
+    Reverse,
+}
+
+impl SendArgs {
+    fn updates(&self) -> Result<Vec<AttributeUpdate>> {
+        let mut updates = Vec::new();
+
+        if let Some(power) = self.power {
+            updates.push(AttributeUpdate::Power(power));
+        }
+        if let Some(brightness) = self.brightness {
+            updates.push(AttributeUpdate::Brightness(NumericUpdate::Absolute(
+                brightness,
+            )));
+        }
+        if let Some(color_temp) = self.color_temp {
+            updates.push(AttributeUpdate::ColorTemp(NumericUpdate::Absolute(
+                color_temp,
+            )));
+        }
+        if let Some(direction) = self.direction {
+            updates.push(AttributeUpdate::FanDirection(match direction {
+                DirectionArg::Forward => FanDirection::Forward,
+                DirectionArg::Reverse => FanDirection::Reverse,
+            }));
+        }
+        if let Some(speed) = self.speed {
+            updates.push(AttributeUpdate::FanSpeed(NumericUpdate::Absolute(speed)));
+        }
+        if let Some(update) = &self.update {
+            updates.push(serde_json::from_str(update).context("failed to parse update")?);
+        }
+
+        if updates.is_empty() {
+            bail!("nothing to send, pass an update or at least one of the flags (see --help)");
+        }
+
+        Ok(updates)
+    }
+}
+
+// Sends updates straight to a fan, without connecting to the server.
+// Nothing is known about the fan beforehand, so updates that depend on the cached
+// state (like a color temperature without a brightness) may not send anything.
+pub async fn send(config: &Config, args: impl Iterator<Item = String>) -> Result<()> {
+    let args = SendArgs::parse_from(iter::once("send".to_owned()).chain(args));
+    let updates = args.updates()?;
+
+    let device_id = args
+        .fan
+        .map(|id| serde_json::from_value::<DeviceId>(Value::String(id)))
+        .transpose()
+        .context("failed to parse device id")?;
+    let fan_config = find_fan(config, device_id)?;
+
+    let adapter = BleAdapter::open(fan_config.hci_device.unwrap_or(config.hci_device), config)?;
+    let mut fan_state = CachedFanState::new(fan_config);
+
+    // Nothing is kept between runs, so every send would start from initial_tx_count and the
+    // fan would ignore it as a repeat of the last one. Going past the last counter fixes that.
+    if let Some(tx_count) = args.tx_count {
+        for (_, remote_tx_count) in &mut fan_state.remotes {
+            *remote_tx_count = tx_count;
+        }
+    }
+
+    // in the same order the update worker would send them in
+    for update in coalesce_updates(updates) {
+        send_update_to_fan(update, &mut fan_state, fan_config, config, &adapter).await?;
+    }
+
+    Ok(())
+}
+
+// never overwrites anything, so running init again can't lose an existing key
+fn write_new_file(path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+// Writes a template config along with a fresh keypair for the controller, to get started
+// without hand-crafting either.
+pub fn init(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let dir = PathBuf::from(args.next().unwrap_or_else(|| ".".to_owned()));
+    if let Some(arg) = args.next() {
+        bail!("unexpected argument {arg:?}\n{INIT_USAGE}");
+    }
+
+    let private_key = SigningKey::random(&mut OsRng);
+
+    let private_key_path = dir.join("private_key.der");
+    let public_key_path = dir.join("public_key.der");
+    let config_path = dir.join("config.toml");
+
+    let private_key_der = private_key
+        .to_pkcs8_der()
+        .context("failed to encode private key")?;
+    let public_key_der = private_key
+        .verifying_key()
+        .to_public_key_der()
+        .context("failed to encode public key")?;
+
+    // the service doesn't run from this directory, so the config gets an absolute path
+    let absolute_key_path = fs::canonicalize(&dir)
+        .with_context(|| format!("failed to resolve {}", dir.display()))?
+        .join("private_key.der");
+    let absolute_key_path = absolute_key_path
+        .to_str()
+        .with_context(|| format!("{} is not valid UTF-8", absolute_key_path.display()))?;
+    let config = CONFIG_TEMPLATE.replace(
+        "{private_key_path}",
+        &toml::Value::String(absolute_key_path.to_owned()).to_string(),
+    );
+
+    write_new_file(&private_key_path, private_key_der.as_bytes(), 0o600)?;
+    write_new_file(&public_key_path, public_key_der.as_bytes(), 0o644)?;
+    write_new_file(&config_path, config.as_bytes(), 0o644)?;
+
+    println!(
+        "wrote {}, {} and {}",
+        config_path.display(),
+        private_key_path.display(),
+        public_key_path.display()
+    );
+
+    Ok(())
+}
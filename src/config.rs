@@ -1,48 +1,502 @@
-use anyhow::Result;
-use devicectrl_common::DeviceId;
+use anyhow::{Context, Result, anyhow, bail};
+use base64ct::{Base64, Encoding};
+use devicectrl_common::{DeviceId, device_types::NumericProperties, updates::AttributeUpdate};
 use p256::{
     ecdsa::{SigningKey, VerifyingKey},
     pkcs8::{DecodePrivateKey, DecodePublicKey},
 };
 use serde::{Deserialize, de};
 use serde_derive::Deserialize;
-use std::{net::SocketAddr, path::Path};
+use std::{collections::HashMap, env, net::SocketAddr, path::Path, str::FromStr};
 use tokio::fs;
 
+use crate::fan::{self, Cmd, EncoderKind, KeepaliveKind, LightKind};
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub device_id: DeviceId,
     pub server_addr: SocketAddr,
-    #[serde(
-        rename = "server_public_key_path",
-        deserialize_with = "deserialize_verifying_key"
-    )]
+    #[serde(flatten, deserialize_with = "deserialize_verifying_key")]
     pub server_public_key: VerifyingKey,
-    #[serde(
-        rename = "private_key_path",
-        deserialize_with = "deserialize_signing_key"
-    )]
+    #[serde(flatten, deserialize_with = "deserialize_signing_key")]
     pub private_key: SigningKey,
-    pub remote_uid: u32,
     pub hci_device: u16,
+    #[serde(default)]
+    pub fans: Vec<FanConfig>,
+    // from before multiple fans were supported, turned into the only entry of fans
+    remote_uid: Option<u32>,
+    #[serde(default = "default_advertise_duration_ms")]
+    pub advertise_duration_ms: u64,
+    #[serde(default = "default_tx_repeat")]
+    pub tx_repeat: u8,
+    #[serde(default = "default_hci_reopen_after_failures")]
+    pub hci_reopen_after_failures: u32,
+    pub allowed_commands: Option<Vec<Cmd>>,
+    #[serde(default)]
+    pub coalesce_window_ms: u64,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default = "default_adv_interval")]
+    pub adv_interval_min: u16,
+    #[serde(default = "default_adv_interval")]
+    pub adv_interval_max: u16,
+    #[serde(default)]
+    pub randomize_adv_address: bool,
+    #[serde(default = "default_adv_channel_map")]
+    pub adv_channel_map: u8,
+    #[serde(default = "default_unreachable_after_failures")]
+    pub unreachable_after_failures: u32,
+    pub metrics_addr: Option<SocketAddr>,
+    pub control_addr: Option<SocketAddr>,
+    #[serde(default = "default_send_timeout_ms")]
+    pub send_timeout_ms: u64,
+    #[serde(default = "default_send_retries")]
+    pub send_retries: u32,
+    #[serde(default)]
+    pub inter_packet_delay_ms: u64,
+    pub intermediate_advertise_duration_ms: Option<u64>,
+    #[serde(default)]
+    pub scan: bool,
+    #[serde(default)]
+    pub batch_packets: bool,
+    #[serde(default)]
+    pub min_send_gap_ms: u64,
+    #[serde(default)]
+    pub sent_log_len: usize,
+    #[serde(default)]
+    pub state_reporting: StateReporting,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateReporting {
+    // report what was just commanded, assuming the fan picked it up
+    #[default]
+    Optimistic,
+    // only report what scanning saw being sent to the fan
+    Confirmed,
+}
+
+impl Config {
+    pub fn intermediate_advertise_duration_ms(&self) -> u64 {
+        self.intermediate_advertise_duration_ms
+            .unwrap_or(self.advertise_duration_ms)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FanConfig {
+    pub device_id: DeviceId,
+    // a list sends every command to each remote in turn, for fans that are always used together
+    #[serde(
+        rename = "remote_uid",
+        alias = "remote_uids",
+        deserialize_with = "deserialize_remote_uids"
+    )]
+    pub remote_uids: Vec<u32>,
+    pub hci_device: Option<u16>,
+    #[serde(default = "default_true")]
+    pub couple_brightness_power: bool,
+    #[serde(default)]
+    pub power_on_last: bool,
+    #[serde(default = "default_keepalive_interval_secs")]
+    pub keepalive_interval_secs: u64,
+    #[serde(default)]
+    pub keepalive_jitter_secs: u64,
+    #[serde(default)]
+    pub keepalive_kind: KeepaliveKind,
+    pub resync_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub brightness_floor: u8,
+    #[serde(default = "default_brightness_gamma")]
+    pub brightness_gamma: f32,
+    #[serde(default = "default_device_type")]
+    pub device_type: u16,
+    #[serde(default = "default_seed")]
+    pub seed: u16,
+    #[serde(default = "default_index")]
+    pub index: u8,
+    // read through speed_max(), None to tell a left out value from one that was set
+    pub speed_max: Option<u8>,
+    #[serde(default)]
+    pub fade_ms: u64,
+    #[serde(default)]
+    pub encoder: EncoderKind,
+    #[serde(default = "default_initial_tx_count")]
+    pub initial_tx_count: u8,
+    #[serde(default)]
+    pub light_kind: LightKind,
+    #[serde(default = "default_sign_key_tail")]
+    pub sign_key_tail: [u8; 13],
+    #[serde(default)]
+    pub brightness_props: PropertiesOverride,
+    #[serde(default)]
+    pub color_temp_props: PropertiesOverride,
+    pub color_temp_min_kelvin: Option<u32>,
+    pub color_temp_max_kelvin: Option<u32>,
+    #[serde(default)]
+    pub speed_props: PropertiesOverride,
+    // named bundles of updates, applied in order when triggered over HTTP
+    #[serde(default)]
+    pub presets: HashMap<String, Vec<AttributeUpdate>>,
+}
+
+impl FanConfig {
+    pub fn speed_max(&self) -> u8 {
+        self.speed_max.unwrap_or(6)
+    }
+}
+
+// anything left out keeps the built in value
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct PropertiesOverride {
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+    pub step: Option<u32>,
+}
+
+impl PropertiesOverride {
+    pub fn apply(&self, props: NumericProperties) -> NumericProperties {
+        NumericProperties {
+            min: self.min.unwrap_or(props.min),
+            max: self.max.unwrap_or(props.max),
+            step: self.step.unwrap_or(props.step),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_keepalive_interval_secs() -> u64 {
+    60 * 60
+}
+
+fn default_brightness_gamma() -> f32 {
+    1.0
+}
+
+fn default_device_type() -> u16 {
+    fan::DEFAULT_DEVICE_TYPE
+}
+
+fn default_seed() -> u16 {
+    fan::DEFAULT_SEED
+}
+
+fn default_index() -> u8 {
+    fan::DEFAULT_INDEX
+}
+
+fn default_initial_tx_count() -> u8 {
+    16 // this is what FanLampPro app initializes with
+}
+
+fn default_sign_key_tail() -> [u8; 13] {
+    fan::DEFAULT_SIGN_KEY_TAIL
+}
+
+fn default_advertise_duration_ms() -> u64 {
+    500
+}
+
+fn default_tx_repeat() -> u8 {
+    1
+}
+
+fn default_hci_reopen_after_failures() -> u32 {
+    3
+}
+
+fn default_adv_interval() -> u16 {
+    32
+}
+
+fn default_adv_channel_map() -> u8 {
+    0x7
+}
+
+fn default_unreachable_after_failures() -> u32 {
+    3
+}
+
+fn default_send_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_send_retries() -> u32 {
+    2
+}
+
+const PEM_PREFIX: &[u8] = b"-----BEGIN";
+
+// keys can either be read from a DER/PEM file or given inline as base64 DER
+#[derive(Deserialize)]
+enum ServerPublicKeySource {
+    #[serde(rename = "server_public_key_path")]
+    Path(String),
+    #[serde(rename = "server_public_key")]
+    Inline(String),
+}
+
+#[derive(Deserialize)]
+enum PrivateKeySource {
+    #[serde(rename = "private_key_path")]
+    Path(String),
+    #[serde(rename = "private_key")]
+    Inline(String),
+}
+
+fn read_key_file(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("failed to read key from {path}"))
+}
+
+fn decode_inline_key(key: &str) -> Result<Vec<u8>> {
+    Base64::decode_vec(key.trim()).map_err(|err| anyhow!("failed to decode key as base64: {err}"))
+}
+
+fn parse_verifying_key(bytes: &[u8]) -> Result<VerifyingKey> {
+    if bytes.starts_with(PEM_PREFIX) {
+        VerifyingKey::from_public_key_pem(str::from_utf8(bytes)?)
+            .context("failed to parse public key as PEM")
+    } else {
+        VerifyingKey::from_public_key_der(bytes).context("failed to parse public key as DER")
+    }
+}
+
+fn parse_signing_key(bytes: &[u8]) -> Result<SigningKey> {
+    if bytes.starts_with(PEM_PREFIX) {
+        SigningKey::from_pkcs8_pem(str::from_utf8(bytes)?)
+            .context("failed to parse private key as PKCS#8 PEM")
+    } else {
+        SigningKey::from_pkcs8_der(bytes).context("failed to parse private key as PKCS#8 DER")
+    }
 }
 
 fn deserialize_verifying_key<'de, D>(deserializer: D) -> Result<VerifyingKey, D::Error>
 where
     D: de::Deserializer<'de>,
 {
-    let der_bytes = std::fs::read(String::deserialize(deserializer)?).map_err(de::Error::custom)?;
-    VerifyingKey::from_public_key_der(&der_bytes).map_err(de::Error::custom)
+    let key_bytes = match ServerPublicKeySource::deserialize(deserializer)? {
+        ServerPublicKeySource::Path(path) => read_key_file(&path),
+        ServerPublicKeySource::Inline(key) => decode_inline_key(&key),
+    };
+
+    key_bytes
+        .and_then(|key_bytes| parse_verifying_key(&key_bytes))
+        .map_err(|err| de::Error::custom(format!("{err:#}")))
 }
 
 pub fn deserialize_signing_key<'de, D>(deserializer: D) -> Result<SigningKey, D::Error>
 where
     D: de::Deserializer<'de>,
 {
-    let der_bytes = std::fs::read(String::deserialize(deserializer)?).map_err(de::Error::custom)?;
-    SigningKey::from_pkcs8_der(&der_bytes).map_err(de::Error::custom)
+    let key_bytes = match PrivateKeySource::deserialize(deserializer)? {
+        PrivateKeySource::Path(path) => read_key_file(&path),
+        PrivateKeySource::Inline(key) => decode_inline_key(&key),
+    };
+
+    key_bytes
+        .and_then(|key_bytes| parse_signing_key(&key_bytes))
+        .map_err(|err| de::Error::custom(format!("{err:#}")))
+}
+
+fn deserialize_remote_uids<'de, D>(deserializer: D) -> Result<Vec<u32>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RemoteUids {
+        One(u32),
+        Many(Vec<u32>),
+    }
+
+    Ok(match RemoteUids::deserialize(deserializer)? {
+        RemoteUids::One(uid) => vec![uid],
+        RemoteUids::Many(uids) => uids,
+    })
+}
+
+// Configs from before multiple fans were supported have a single remote_uid at the top
+// level, and the controller's device_id doubles as the fan's. Everything else is left at
+// its default, just like it was back then.
+fn migrate_legacy_fan(config: &mut Config) -> Result<()> {
+    let Some(remote_uid) = config.remote_uid.take() else {
+        return Ok(());
+    };
+    if !config.fans.is_empty() {
+        bail!("remote_uid can't be set at the top level together with fans, move it into the fan");
+    }
+
+    log::warn!("remote_uid at the top level is deprecated, configure the fan under fans instead");
+    config.fans.push(
+        serde_json::from_value(serde_json::json!({
+            "device_id": config.device_id,
+            "remote_uid": remote_uid,
+        }))
+        .context("failed to build fan from top level remote_uid")?,
+    );
+
+    Ok(())
+}
+
+fn env_override<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .with_context(|| format!("invalid value for {name}")),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("invalid value for {name}")),
+    }
+}
+
+// lets per host values be changed without editing a shared config file
+fn apply_env_overrides(config: &mut Config) -> Result<()> {
+    if let Some(server_addr) = env_override("DEVICECTRL_SERVER_ADDR")? {
+        config.server_addr = server_addr;
+    }
+    if let Some(hci_device) = env_override("DEVICECTRL_HCI_DEVICE")? {
+        config.hci_device = hci_device;
+    }
+    if let Some(remote_uid) = env_override("DEVICECTRL_REMOTE_UID")? {
+        let [fan] = config.fans.as_mut_slice() else {
+            bail!("DEVICECTRL_REMOTE_UID can only be used when exactly one fan is configured");
+        };
+        fan.remote_uids = vec![remote_uid];
+    }
+
+    Ok(())
 }
 
 pub async fn load_config(path: &Path) -> Result<Config> {
-    Ok(serde_json::from_slice(&fs::read(path).await?)?)
+    let contents = fs::read(path).await?;
+
+    let mut config: Config = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => {
+            toml::from_str(str::from_utf8(&contents).context("TOML config is not valid UTF-8")?)?
+        }
+        _ => serde_json::from_slice(&contents)?,
+    };
+
+    migrate_legacy_fan(&mut config)?;
+    apply_env_overrides(&mut config)?;
+
+    if config.fans.is_empty() {
+        bail!("at least one fan must be configured");
+    }
+
+    // the range the spec allows for connectable undirected advertising, 20ms to 10.24s
+    if !(0x20..=0x4000).contains(&config.adv_interval_min)
+        || !(0x20..=0x4000).contains(&config.adv_interval_max)
+    {
+        bail!("adv_interval_min and adv_interval_max must be between 32 and 16384");
+    }
+    if config.adv_interval_min > config.adv_interval_max {
+        bail!("adv_interval_min must not be greater than adv_interval_max");
+    }
+
+    if config.state_reporting == StateReporting::Confirmed && !config.scan {
+        bail!("state_reporting = \"confirmed\" needs scan to be enabled");
+    }
+
+    // anyone who can connect can change the fans
+    if let Some(addr) = config.control_addr
+        && !addr.ip().is_loopback()
+    {
+        bail!("control_addr must be a loopback address, the control server has no authentication");
+    }
+
+    // one bit per channel, 37 to 39
+    if !(0x1..=0x7).contains(&config.adv_channel_map) {
+        bail!("adv_channel_map must be between 1 and 7");
+    }
+
+    if let Some(fan) = config.fans.iter().find(|fan| fan.speed_max == Some(0)) {
+        bail!("speed_max of fan {:?} must be at least 1", fan.device_id);
+    }
+
+    // one would silently win over the other
+    if let Some(fan) = config
+        .fans
+        .iter()
+        .find(|fan| fan.speed_max.is_some() && fan.speed_props.max.is_some())
+    {
+        bail!(
+            "fan {:?} can't set both speed_max and speed_props.max",
+            fan.device_id
+        );
+    }
+
+    if let Some(fan) = config
+        .fans
+        .iter()
+        .find(|fan| !fan.brightness_gamma.is_finite() || fan.brightness_gamma <= 0.)
+    {
+        bail!(
+            "brightness_gamma of fan {:?} must be a finite number above 0",
+            fan.device_id
+        );
+    }
+
+    for fan in &config.fans {
+        if fan.remote_uids.is_empty() {
+            bail!("fan {:?} needs at least one remote_uid", fan.device_id);
+        }
+        if (1..fan.remote_uids.len())
+            .any(|i| fan.remote_uids[i..].contains(&fan.remote_uids[i - 1]))
+        {
+            bail!(
+                "fan {:?} lists the same remote_uid more than once",
+                fan.device_id
+            );
+        }
+    }
+
+    for fan in &config.fans {
+        match (fan.color_temp_min_kelvin, fan.color_temp_max_kelvin) {
+            (None, None) => {}
+            (Some(min), Some(max)) if min < max => {
+                if fan.color_temp_props != PropertiesOverride::default() {
+                    bail!(
+                        "fan {:?} can't use color_temp_props together with a Kelvin range",
+                        fan.device_id
+                    );
+                }
+            }
+            _ => bail!(
+                "fan {:?} needs both color_temp_min_kelvin and color_temp_max_kelvin, with min below max",
+                fan.device_id
+            ),
+        }
+    }
+
+    // cached values are kept in a byte
+    for fan in &config.fans {
+        for (name, props) in [
+            ("brightness_props", fan.brightness_props),
+            ("color_temp_props", fan.color_temp_props),
+            ("speed_props", fan.speed_props),
+        ] {
+            if props
+                .max
+                .is_some_and(|max| max == 0 || max > u8::MAX as u32)
+                || props.step == Some(0)
+                || props.min.zip(props.max).is_some_and(|(min, max)| min > max)
+            {
+                bail!(
+                    "{name} of fan {:?} needs a max between 1 and 255, a step of at least 1 and min not above max",
+                    fan.device_id
+                );
+            }
+        }
+    }
+
+    Ok(config)
 }
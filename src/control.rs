@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use devicectrl_common::{DeviceId, updates::AttributeUpdate};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, mpsc},
+};
+
+use crate::{config::FanConfig, fan::CachedFanState};
+
+pub struct FanHandle {
+    pub device_id: DeviceId,
+    pub config: &'static FanConfig,
+    pub state: &'static Mutex<CachedFanState>,
+    // where requests are queued, like updates from the server
+    pub updates: mpsc::UnboundedSender<AttributeUpdate>,
+}
+
+type Fans = Vec<FanHandle>;
+
+// Lets things on the same host change the fans without going through the server. Like the
+// metrics server this is not a real HTTP server, and there is no authentication at all, so
+// it should only ever listen on localhost. It answers POST /preset/<name> and
+// POST /light/toggle.
+pub async fn serve(addr: SocketAddr, fans: Fans) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind control server to {addr}"))?;
+    let fans: &'static Fans = Box::leak(Box::new(fans));
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, fans).await {
+                log::debug!("{:?}", err.context("failed to answer control request"));
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, fans: &Fans) -> Result<()> {
+    let mut request = [0u8; 1024];
+    let len = stream.read(&mut request).await?;
+    let mut request_line = str::from_utf8(&request[..len])
+        .unwrap_or_default()
+        .split_whitespace();
+    let method = request_line.next();
+    let path = request_line.next().unwrap_or_default();
+
+    let (status, body) = match (method, path.strip_prefix("/preset/")) {
+        (Some("POST"), Some(name)) => apply_preset(name, fans),
+        (Some("POST"), None) if path == "/light/toggle" => toggle_light(fans).await,
+        _ => ("404 Not Found", "unknown request\n".to_owned()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+// Queues the preset's updates on every fan that has a preset with this name. They go through
+// the same worker as updates from the server, so the server hears about the new state.
+fn apply_preset(name: &str, fans: &Fans) -> (&'static str, String) {
+    let mut applied = Vec::new();
+    for fan in fans {
+        let Some(updates) = fan.config.presets.get(name) else {
+            continue;
+        };
+
+        for update in updates {
+            if fan.updates.send(update.clone()).is_err() {
+                return (
+                    "503 Service Unavailable",
+                    "update worker has stopped\n".to_owned(),
+                );
+            }
+        }
+        applied.push(format!("{:?}", fan.device_id));
+    }
+
+    if applied.is_empty() {
+        return ("404 Not Found", format!("no fan has a preset {name:?}\n"));
+    }
+
+    log::info!("applying preset {name:?} to {}", applied.join(", "));
+    ("200 OK", format!("applied to {}\n", applied.join(", ")))
+}
+
+// For single button switches that don't know whether the light is on. Each fan is flipped
+// from its own cached power, and one that was never turned on counts as off.
+async fn toggle_light(fans: &Fans) -> (&'static str, String) {
+    let mut toggled = Vec::new();
+    for fan in fans {
+        let power = !fan.state.lock().await.power.unwrap_or(false);
+
+        if fan.updates.send(AttributeUpdate::Power(power)).is_err() {
+            return (
+                "503 Service Unavailable",
+                "update worker has stopped\n".to_owned(),
+            );
+        }
+        toggled.push(format!(
+            "{:?} {}",
+            fan.device_id,
+            if power { "on" } else { "off" }
+        ));
+    }
+
+    log::info!("toggling light: {}", toggled.join(", "));
+    ("200 OK", format!("turned {}\n", toggled.join(", ")))
+}
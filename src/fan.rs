@@ -2,15 +2,30 @@ use aes::{
     Aes128,
     cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray},
 };
-use anyhow::{Result, bail};
 use crc::{CRC_16_XMODEM, Crc};
 use devicectrl_common::{
-    device_types::{NumericProperties, ceiling_fan::FanDirection},
-    updates::AttributeUpdate,
+    DeviceState,
+    device_types::{
+        NumericProperties,
+        ceiling_fan::{CeilingFanState, FanDirection},
+    },
+    updates::{AttributeUpdate, NumericUpdate},
 };
-use hciraw::HciSocket;
+use serde_derive::Deserialize;
+use std::{
+    collections::VecDeque,
+    error, fmt, mem,
+    sync::atomic::Ordering,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::time::sleep;
+use tracing::Instrument;
 
-use crate::ble::advertise_ble_message;
+use crate::{
+    ble::BleAdapter,
+    config::{Config, FanConfig},
+    metrics,
+};
 
 const BRIGHTNESS_PROPS: NumericProperties = NumericProperties {
     min: 0,
@@ -24,11 +39,74 @@ const COLOR_TEMP_PROPS: NumericProperties = NumericProperties {
     step: 1,
 };
 
-const SPEED_PROPS: NumericProperties = NumericProperties {
-    min: 0,
-    max: 6,
-    step: 1,
-};
+// the cached values are in these units, they are only scaled to the raw 0-255 when sent
+fn brightness_props(fan_config: &FanConfig) -> NumericProperties {
+    fan_config.brightness_props.apply(BRIGHTNESS_PROPS)
+}
+
+// in Kelvin if a range is configured
+fn color_temp_props(fan_config: &FanConfig) -> NumericProperties {
+    match kelvin_range(fan_config) {
+        Some((min, max)) => NumericProperties { min, max, step: 1 },
+        None => fan_config.color_temp_props.apply(COLOR_TEMP_PROPS),
+    }
+}
+
+fn kelvin_range(fan_config: &FanConfig) -> Option<(u32, u32)> {
+    fan_config
+        .color_temp_min_kelvin
+        .zip(fan_config.color_temp_max_kelvin)
+}
+
+// Kelvin doesn't fit in a byte, so those temperatures are cached as the raw 0-255 instead.
+// Both directions round, so a temperature read back converts to the same byte again.
+fn color_temp_to_cached(color_temp: u32, fan_config: &FanConfig) -> u8 {
+    match kelvin_range(fan_config) {
+        Some((min, max)) => {
+            let range = max - min;
+            (((color_temp.clamp(min, max) - min) * 255 + range / 2) / range) as u8
+        }
+        None => color_temp as u8,
+    }
+}
+
+fn color_temp_from_cached(color_temp: u8, fan_config: &FanConfig) -> u32 {
+    match kelvin_range(fan_config) {
+        Some((min, max)) => min + (color_temp as u32 * (max - min) + 127) / 255,
+        None => color_temp as u32,
+    }
+}
+
+// the number of speeds differs between models
+fn speed_props(fan_config: &FanConfig) -> NumericProperties {
+    fan_config.speed_props.apply(NumericProperties {
+        min: 0,
+        max: fan_config.speed_max() as u32,
+        step: 1,
+    })
+}
+
+// Percentages are rounded half up, so the speeds split the range evenly: 50% of a 3-speed
+// fan is speed 2, and 16% of a 6-speed fan is already speed 1.
+fn speed_from_percentage(percent: u32, props: &NumericProperties) -> u32 {
+    props.min + ((props.max - props.min) * percent.min(100) + 50) / 100
+}
+
+fn resolve_speed(update: &NumericUpdate, current: u8, fan_config: &FanConfig) -> u8 {
+    let props = speed_props(fan_config);
+    match update {
+        NumericUpdate::Percentage(percent) => speed_from_percentage(*percent as u32, &props) as u8,
+        _ => update.apply_to(&props.to_state(current as u32)) as u8,
+    }
+}
+
+// halfway between warm and cool, which drives both channels fully
+fn neutral_color_temp(fan_config: &FanConfig) -> u8 {
+    match kelvin_range(fan_config) {
+        Some(_) => 127,
+        None => (color_temp_props(fan_config).max / 2) as u8,
+    }
+}
 
 // Values and algorithms derived from https://github.com/NicoIIT/ha-ble-adv
 
@@ -49,27 +127,197 @@ const XOR_LUT: [u8; 128] = [
     0x8C, 0xA1, 0x89, 0x0D, 0xBF, 0xE6, 0x42, 0x68, 0x41, 0x99, 0x2D, 0x0F, 0xB0, 0x54, 0xBB, 0x16,
 ];
 
-const SEED: u16 = 0x2B53;
-const INDEX: u8 = 0;
-const DEVICE_TYPE: u16 = 1024;
+// defaults for the FanLampPro remote, other remotes can override these per fan
+pub const DEFAULT_SEED: u16 = 0x2B53;
+pub const DEFAULT_INDEX: u8 = 0;
+pub const DEFAULT_DEVICE_TYPE: u16 = 1024;
+
+// There is no variant for encrypting a packet: the AES key and block are built from fixed
+// size arrays, and whitening and the CRC only work on bytes already in the packet, so
+// nothing about encoding can fail at runtime.
+#[derive(Debug)]
+pub enum FanError {
+    // the adapter failed to advertise the packet
+    BleSend(anyhow::Error),
+    // the adapter didn't finish advertising within send_timeout_ms
+    Timeout,
+    InvalidArgument(String),
+}
+
+impl fmt::Display for FanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::BleSend(_) => write!(f, "failed to advertise packet"),
+            Self::Timeout => write!(f, "timed out advertising packet"),
+            Self::InvalidArgument(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl error::Error for FanError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::BleSend(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 // Because the fan uses the same command for brightness and color temperature,
 // we need to cache the state of the fan to remember the last brightness and temperature
 // values, so we can send the correct command when only one of them changes.
-// TODO: maybe use Options to represent the initial unknown state?
-#[derive(Debug)]
+// Values are None until they have been commanded at least once since startup.
+#[derive(Clone, Debug)]
 pub struct CachedFanState {
+    pub power: Option<bool>,
+    pub color_temp: Option<u8>,
+    pub brightness: Option<u8>,
+    pub speed: Option<u8>,
+    pub direction: Option<FanDirection>,
+
+    // (remote uid, tx_count) for every remote this fan listens to
+    // not actually fan state, but convenient to store here
+    pub remotes: Vec<(u32, u8)>,
+    pub last_sent: Option<Instant>,
+    // the most recent packets, to see whether one went missing when the fan gets out of sync
+    pub sent_log: VecDeque<SentPacket>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SentPacket {
+    pub timestamp: SystemTime,
+    pub uid: u32,
     pub tx_count: u8,
-    pub power: bool,
-    pub color_temp: u8,
-    pub brightness: u8,
-    pub speed: u8,
+    pub cmd: u8,
+    pub args: [u8; 3],
+    // whether the adapter advertised it, not whether the fan picked it up
+    pub sent: bool,
+}
+
+impl CachedFanState {
+    pub fn new(fan_config: &FanConfig) -> Self {
+        Self {
+            power: None,
+            color_temp: None,
+            brightness: None,
+            speed: None,
+            direction: None,
+
+            remotes: fan_config
+                .remote_uids
+                .iter()
+                .map(|&uid| (uid, fan_config.initial_tx_count))
+                .collect(),
+            last_sent: None,
+            sent_log: VecDeque::new(),
+        }
+    }
+    // we can't read anything back from the fan, so this is just the last commanded state
+    pub fn to_device_state(&self, fan_config: &FanConfig) -> DeviceState {
+        if self.power.is_none()
+            && self.brightness.is_none()
+            && self.color_temp.is_none()
+            && self.speed.is_none()
+            && self.direction.is_none()
+        {
+            return DeviceState::Unknown;
+        }
+
+        DeviceState::CeilingFan(CeilingFanState {
+            power: self.power.unwrap_or_default(),
+            brightness: brightness_props(fan_config)
+                .to_state(self.brightness.unwrap_or_default() as u32),
+            color_temp: color_temp_props(fan_config).to_state(color_temp_from_cached(
+                self.color_temp.unwrap_or(neutral_color_temp(fan_config)),
+                fan_config,
+            )),
+            fan_speed: speed_props(fan_config).to_state(self.speed.unwrap_or_default() as u32),
+            fan_direction: self.direction.unwrap_or(FanDirection::Forward),
+        })
+    }
+    // when power follows brightness, the light can't be on at zero brightness
+    // (it can still be off at a non-zero brightness after an explicit power off)
+    fn normalize(&mut self, fan_config: &FanConfig) {
+        if !fan_config.couple_brightness_power {
+            return;
+        }
+
+        let consistent = !(self.power == Some(true) && self.brightness == Some(0));
+        debug_assert!(consistent, "cached power out of sync with brightness");
+
+        if !consistent {
+            log::warn!("cached power was on at zero brightness, correcting");
+            self.power = Some(false);
+        }
+    }
+    fn record_sent(&mut self, packet: &PacketData, sent: bool, config: &Config) {
+        if config.sent_log_len == 0 {
+            return;
+        }
+
+        while self.sent_log.len() >= config.sent_log_len {
+            self.sent_log.pop_front();
+        }
+        self.sent_log.push_back(SentPacket {
+            timestamp: SystemTime::now(),
+            uid: packet.uid,
+            tx_count: packet.tx_count,
+            cmd: packet.cmd,
+            args: [packet.arg0, packet.arg1, packet.arg2],
+            sent,
+        });
+    }
+    // Applies a packet someone else sent to this fan, usually its physical remote.
+    // Returns whether anything changed.
+    pub fn observe(&mut self, packet: &PacketData, fan_config: &FanConfig) -> bool {
+        if packet.device_type != fan_config.device_type || packet.index != fan_config.index {
+            return false;
+        }
+        let Some((_, tx_count)) = self.remotes.iter_mut().find(|(uid, _)| *uid == packet.uid)
+        else {
+            return false;
+        };
+
+        // remotes repeat every packet, and anything behind us is one we already know about
+        // (or sent ourselves)
+        if packet.tx_count.wrapping_sub(*tx_count) >= 0x80 {
+            return false;
+        }
+        // the fan ignores counters that went backwards, so continue after the remote's
+        *tx_count = packet.tx_count.wrapping_add(1);
+
+        log::debug!("observed packet for this fan: {packet:?}");
+
+        let before = (self.power, self.brightness, self.speed, self.direction);
+        match packet.cmd {
+            cmd if cmd == Cmd::LightOn as u8 => {
+                self.power = Some(true);
+
+                // the fan comes back at its last brightness, which can't have been zero
+                if self.brightness == Some(0) {
+                    self.brightness = Some(brightness_props(fan_config).max as u8);
+                }
+            }
+            cmd if cmd == Cmd::LightOff as u8 => self.power = Some(false),
+            cmd if cmd == Cmd::FanSpeed as u8 => self.speed = Some(packet.arg1),
+            cmd if cmd == Cmd::Direction as u8 => {
+                self.direction = Some(match packet.arg0 {
+                    0 => FanDirection::Forward,
+                    _ => FanDirection::Reverse,
+                });
+            }
+            // the channels went through gamma and the floor, so they can't be mapped back
+            _ => {}
+        }
 
-    pub remote_uid: u32, // not actually fan state, but convenient to store here
+        (self.power, self.brightness, self.speed, self.direction) != before
+    }
 }
 
 #[repr(u8)]
-enum Cmd {
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cmd {
     Direction = 0x15,
     FanSpeed = 0x31,
     LightOn = 0x10,
@@ -79,7 +327,7 @@ enum Cmd {
 }
 
 #[derive(Debug)]
-struct SerializedPacket(pub [u8; PACKET_LEN]);
+pub struct SerializedPacket(pub [u8; PACKET_LEN]);
 
 #[derive(Debug)]
 pub struct EncryptedPacket(pub [u8; ENCRYPTED_PACKET_LEN]);
@@ -87,6 +335,10 @@ pub struct EncryptedPacket(pub [u8; ENCRYPTED_PACKET_LEN]);
 #[derive(Debug)]
 pub struct WrappedPacket(pub [u8; ENCRYPTED_PACKET_LEN + 5]);
 
+// the lengths end up in single byte length prefixes, here and when advertising
+const _: () = assert!(ENCRYPTED_PACKET_LEN + 1 <= u8::MAX as usize);
+const _: () = assert!(size_of::<WrappedPacket>() <= u8::MAX as usize);
+
 pub fn wrap_packet(packet: &EncryptedPacket) -> WrappedPacket {
     let mut buf = [0u8; size_of::<WrappedPacket>()];
 
@@ -96,8 +348,32 @@ pub fn wrap_packet(packet: &EncryptedPacket) -> WrappedPacket {
     WrappedPacket(buf)
 }
 
-#[derive(Debug)]
-struct PacketData {
+// builds a complete packet from explicit fields with the default encoder, without any cached state
+pub fn build_wrapped_packet(
+    tx_count: u8,
+    uid: u32,
+    cmd: Cmd,
+    args: [u8; 3],
+    device_type: u16,
+    seed: u16,
+) -> WrappedPacket {
+    let packet = PacketData {
+        tx_count,
+        device_type,
+        uid,
+        index: DEFAULT_INDEX,
+        cmd: cmd as u8,
+        arg0: args[0],
+        arg1: args[1],
+        arg2: args[2],
+        seed,
+    };
+
+    wrap_packet(&encrypt(&packet.serialize(), &DEFAULT_SIGN_KEY_TAIL))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PacketData {
     // PACKET_HEADER here
     tx_count: u8,
     device_type: u16,
@@ -112,103 +388,338 @@ struct PacketData {
 }
 
 impl PacketData {
-    fn from_command(update: &AttributeUpdate, fan_state: &mut CachedFanState) -> Vec<Self> {
+    pub fn from_command(
+        update: &AttributeUpdate,
+        fan_state: &mut CachedFanState,
+        fan_config: &FanConfig,
+        allowed_commands: Option<&[Cmd]>,
+        fade_steps: u32,
+    ) -> Vec<Self> {
+        let Some(allowed_commands) = allowed_commands else {
+            return Self::build(update, fan_state, fan_config, fade_steps);
+        };
+
+        // An update can need more than its own command, like brightness turning the light on,
+        // so it is built on a copy and only kept if every command it needs is allowed.
+        let mut new_state = fan_state.clone();
+        let packets = Self::build(update, &mut new_state, fan_config, fade_steps);
+        if let Some(packet) = packets
+            .iter()
+            .find(|packet| !allowed_commands.iter().any(|&cmd| cmd as u8 == packet.cmd))
+        {
+            log::warn!(
+                "dropping update that needs disallowed command {:#04x}: {update:?}",
+                packet.cmd
+            );
+            return Vec::new();
+        }
+
+        *fan_state = new_state;
+        packets
+    }
+    fn build(
+        update: &AttributeUpdate,
+        fan_state: &mut CachedFanState,
+        fan_config: &FanConfig,
+        fade_steps: u32,
+    ) -> Vec<Self> {
+        if !matches!(
+            update,
+            AttributeUpdate::Power(_)
+                | AttributeUpdate::Brightness(_)
+                | AttributeUpdate::ColorTemp(_)
+                | AttributeUpdate::FanDirection(_)
+                | AttributeUpdate::FanSpeed(_)
+        ) {
+            log::warn!("fan does not support attribute update: {update:?}");
+            return Vec::new();
+        }
+
+        if Self::is_noop(update, fan_state, fan_config) {
+            log::debug!("skipping update that doesn't change anything: {update:?}");
+            return Vec::new();
+        }
+
         let mut packets = Vec::new();
+        let mut power_cmd = None;
+
+        // only fade while the light stays on, turning it on or off is still instant
+        let fade_from = match update {
+            AttributeUpdate::Brightness(_) if fan_state.power == Some(true) => fan_state.brightness,
+            _ => None,
+        };
+
+        // brightness is left alone so the light comes back at the same level
+        if let AttributeUpdate::Power(power) = update {
+            fan_state.power = Some(*power);
+
+            // unless it was at zero, in which case turning on would leave it dark
+            if *power && fan_state.brightness == Some(0) {
+                fan_state.brightness = Some(brightness_props(fan_config).max as u8);
+            }
+
+            power_cmd = Some(match power {
+                true => Cmd::LightOn,
+                false => Cmd::LightOff,
+            });
+        }
 
         if let AttributeUpdate::Brightness(brightness) = &update {
-            let brightness =
-                brightness.apply_to(&BRIGHTNESS_PROPS.to_state(fan_state.brightness as u32)) as u8;
+            let brightness = brightness.apply_to(
+                &brightness_props(fan_config)
+                    .to_state(fan_state.brightness.unwrap_or_default() as u32),
+            ) as u8;
 
-            fan_state.brightness = brightness;
+            fan_state.brightness = Some(brightness);
 
             // the fan has a power state, so we need to send a command to turn it on or off
             // because the api does not have a separate power state, it just has brightness
-            if (brightness != 0) != fan_state.power {
-                fan_state.power = brightness != 0;
-                packets.push(Self::new(
-                    fan_state.tx_count,
-                    fan_state.remote_uid,
-                    match brightness {
-                        0 => Cmd::LightOff,
-                        _ => Cmd::LightOn,
-                    },
-                    [0, 0, 0],
-                ));
-
-                fan_state.tx_count = fan_state.tx_count.wrapping_add(1);
+            // servers that manage power themselves can turn this coupling off
+            if fan_config.couple_brightness_power && fan_state.power != Some(brightness != 0) {
+                fan_state.power = Some(brightness != 0);
+                power_cmd = Some(match brightness {
+                    0 => Cmd::LightOff,
+                    _ => Cmd::LightOn,
+                });
             }
         }
 
+        // the off command already covers a brightness of 0
+        let turning_off = matches!(power_cmd, Some(Cmd::LightOff));
+
+        // turning the light on last lets it come up at the new brightness instead of the old one
+        let power_on_last = fan_config.power_on_last && matches!(power_cmd, Some(Cmd::LightOn));
+        if !power_on_last && let Some(cmd) = power_cmd.take() {
+            packets.extend(Self::next(fan_state, fan_config, cmd, [0, 0, 0]));
+        }
+
         if let AttributeUpdate::ColorTemp(color_temp) = update {
-            fan_state.color_temp =
-                color_temp.apply_to(&COLOR_TEMP_PROPS.to_state(fan_state.color_temp as u32)) as u8;
+            let current = fan_state
+                .color_temp
+                .unwrap_or(neutral_color_temp(fan_config));
+            fan_state.color_temp = Some(color_temp_to_cached(
+                color_temp.apply_to(
+                    &color_temp_props(fan_config)
+                        .to_state(color_temp_from_cached(current, fan_config)),
+                ) as u32,
+                fan_config,
+            ));
         }
 
-        if matches!(
-            update,
-            AttributeUpdate::Brightness(_) | AttributeUpdate::ColorTemp(_)
-        ) {
-            let brightness = fan_state.brightness as f32;
-            let temperature = fan_state.color_temp as f32;
+        // a temperature change at zero brightness would just send two zero channels
+        let light_changed = match update {
+            AttributeUpdate::Brightness(_) => !turning_off,
+            AttributeUpdate::ColorTemp(_) => {
+                fan_config.light_kind == LightKind::Dual && fan_state.brightness != Some(0)
+            }
+            _ => false,
+        };
+
+        // the light may be off with a non-zero brightness, so don't wake it up. Without the
+        // coupling any light packet could turn it on, so it has to be known to be on already.
+        let may_send_light = match fan_config.couple_brightness_power {
+            true => fan_state.power != Some(false) || fan_state.brightness == Some(0),
+            false => fan_state.power == Some(true),
+        };
+
+        if ((light_changed && may_send_light) || matches!(update, AttributeUpdate::Power(true)))
+            && let Some(args) = Self::light_args(fan_state, fan_config)
+        {
+            // the steps are sent one after another, so they are paced by the advertising
+            if let Some(from) = fade_from
+                && let Some(to) = fan_state.brightness
+                && fan_state.power == Some(true)
+            {
+                let steps = fade_steps.min(from.abs_diff(to) as u32);
+                for step in 1..steps {
+                    let brightness =
+                        from as i32 + (to as i32 - from as i32) * step as i32 / steps as i32;
+                    let args = Self::light_args_at(brightness as u8, fan_state, fan_config);
+
+                    packets.extend(Self::next(
+                        fan_state,
+                        fan_config,
+                        Cmd::LightBrightnessTemperature,
+                        args,
+                    ));
+                }
+            }
 
-            packets.push(Self::new(
-                fan_state.tx_count,
-                fan_state.remote_uid,
+            packets.extend(Self::next(
+                fan_state,
+                fan_config,
                 Cmd::LightBrightnessTemperature,
-                [
-                    0,
-                    (brightness * ((255. - temperature).min(127.) / 127.)).ceil() as u8,
-                    (brightness * temperature.min(127.) / 127.).ceil() as u8,
-                ],
+                args,
             ));
-            fan_state.tx_count = fan_state.tx_count.wrapping_add(1);
+        }
+
+        if let Some(cmd) = power_cmd {
+            packets.extend(Self::next(fan_state, fan_config, cmd, [0, 0, 0]));
         }
 
         if let AttributeUpdate::FanDirection(fan_direction) = &update {
-            packets.push(Self::new(
-                fan_state.tx_count,
-                fan_state.remote_uid,
+            fan_state.direction = Some(*fan_direction);
+            packets.extend(Self::next(
+                fan_state,
+                fan_config,
                 Cmd::Direction,
-                [
-                    match fan_direction {
-                        FanDirection::Forward => 0,
-                        FanDirection::Reverse => 1,
-                    },
-                    0,
-                    0,
-                ],
+                [direction_arg(*fan_direction), 0, 0],
             ));
-            fan_state.tx_count = fan_state.tx_count.wrapping_add(1);
         }
 
         if let AttributeUpdate::FanSpeed(fan_speed) = &update {
-            let fan_speed = fan_speed.apply_to(&SPEED_PROPS.to_state(fan_state.speed as u32)) as u8;
+            let fan_speed =
+                resolve_speed(fan_speed, fan_state.speed.unwrap_or_default(), fan_config);
 
-            packets.push(Self::new(
-                fan_state.tx_count,
-                fan_state.remote_uid,
+            // The remote has no separate fan power command: speed 0 is how it turns the fan
+            // off, and any other speed starts it again. So the cached speed doubles as power.
+            if (fan_speed == 0) != (fan_state.speed == Some(0)) {
+                log::debug!("turning fan {}", if fan_speed == 0 { "off" } else { "on" });
+            }
+            fan_state.speed = Some(fan_speed);
+
+            packets.extend(Self::next(
+                fan_state,
+                fan_config,
                 Cmd::FanSpeed,
                 [32, fan_speed, 0],
             ));
-            fan_state.tx_count = fan_state.tx_count.wrapping_add(1);
         }
 
+        fan_state.normalize(fan_config);
+
         packets
     }
-    fn new(tx_count: u8, uid: u32, cmd: Cmd, args: [u8; 3]) -> Self {
+    // chatty automations like to re-assert state, which doesn't need to go out again
+    fn is_noop(
+        update: &AttributeUpdate,
+        fan_state: &CachedFanState,
+        fan_config: &FanConfig,
+    ) -> bool {
+        match update {
+            AttributeUpdate::Brightness(brightness) => {
+                let Some(current) = fan_state.brightness else {
+                    return false;
+                };
+                let target = brightness
+                    .apply_to(&brightness_props(fan_config).to_state(current as u32))
+                    as u8;
+
+                // re-asserting a brightness can still turn a coupled light on or off
+                target == current
+                    && (!fan_config.couple_brightness_power || fan_state.power == Some(target != 0))
+            }
+            AttributeUpdate::ColorTemp(color_temp) => fan_state.color_temp.is_some_and(|current| {
+                let target = color_temp.apply_to(
+                    &color_temp_props(fan_config)
+                        .to_state(color_temp_from_cached(current, fan_config)),
+                ) as u32;
+
+                color_temp_to_cached(target, fan_config) == current
+            }),
+            AttributeUpdate::FanDirection(direction) => fan_state.direction == Some(*direction),
+            AttributeUpdate::FanSpeed(speed) => fan_state
+                .speed
+                .is_some_and(|current| resolve_speed(speed, current, fan_config) == current),
+            // power is always sent, it's the easiest way to fix a fan that got out of sync
+            _ => false,
+        }
+    }
+    // there is nothing sensible to send until the brightness is known, which avoids
+    // flashing the light to some made up brightness on a temperature-only update
+    fn light_args(fan_state: &CachedFanState, fan_config: &FanConfig) -> Option<[u8; 3]> {
+        Some(Self::light_args_at(
+            fan_state.brightness?,
+            fan_state,
+            fan_config,
+        ))
+    }
+    // splits the brightness across the warm and cool channels according to the temperature
+    fn light_args_at(
+        brightness: u8,
+        fan_state: &CachedFanState,
+        fan_config: &FanConfig,
+    ) -> [u8; 3] {
+        let brightness = match brightness {
+            0 => 0,
+            brightness => {
+                let brightness = brightness as f32 / brightness_props(fan_config).max as f32;
+
+                // the cached brightness stays linear, only what gets sent is corrected
+                let corrected = (255. * brightness.powf(fan_config.brightness_gamma)).ceil() as u8;
+
+                // some LEDs flicker or cut out when driven too low
+                corrected.max(fan_config.brightness_floor)
+            }
+        } as f32;
+
+        // there is no temperature to split by, so both channels get the full brightness
+        // and the fan uses whichever one it has
+        if fan_config.light_kind == LightKind::Single {
+            return [0, brightness as u8, brightness as u8];
+        }
+
+        let temperature = fan_state
+            .color_temp
+            .unwrap_or(neutral_color_temp(fan_config)) as f32;
+        let temperature = match kelvin_range(fan_config) {
+            Some(_) => temperature,
+            None => temperature * 255. / color_temp_props(fan_config).max as f32,
+        };
+
+        [
+            0,
+            (brightness * ((255. - temperature).min(127.) / 127.)).ceil() as u8,
+            (brightness * temperature.min(127.) / 127.).ceil() as u8,
+        ]
+    }
+    // like next, but a disallowed command is dropped before it uses up a tx_count
+    fn next_allowed(
+        fan_state: &mut CachedFanState,
+        fan_config: &FanConfig,
+        allowed_commands: Option<&[Cmd]>,
+        cmd: Cmd,
+        args: [u8; 3],
+    ) -> Vec<Self> {
+        if !is_allowed(cmd, allowed_commands) {
+            log::warn!("dropping packet with disallowed command {cmd:?}");
+            return Vec::new();
+        }
+
+        Self::next(fan_state, fan_config, cmd, args)
+    }
+    // builds the next packet for every remote of this fan, each consuming its own tx_count
+    pub fn next(
+        fan_state: &mut CachedFanState,
+        fan_config: &FanConfig,
+        cmd: Cmd,
+        args: [u8; 3],
+    ) -> Vec<Self> {
+        fan_state
+            .remotes
+            .iter_mut()
+            .map(|(uid, tx_count)| {
+                let packet = Self::new(*tx_count, *uid, fan_config, cmd, args);
+                *tx_count = tx_count.wrapping_add(1);
+
+                packet
+            })
+            .collect()
+    }
+    fn new(tx_count: u8, uid: u32, fan_config: &FanConfig, cmd: Cmd, args: [u8; 3]) -> Self {
         Self {
             tx_count,
-            device_type: DEVICE_TYPE,
+            device_type: fan_config.device_type,
             uid,
-            index: INDEX,
+            index: fan_config.index,
             cmd: cmd as u8,
             arg0: args[0],
             arg1: args[1],
             arg2: args[2],
-            seed: SEED,
+            seed: fan_config.seed,
         }
     }
-    fn serialize(&self) -> SerializedPacket {
+    pub fn serialize(&self) -> SerializedPacket {
         let mut buf = [0u8; 19];
 
         buf[0..=2].copy_from_slice(&PACKET_HEADER);
@@ -224,11 +735,12 @@ impl PacketData {
 
         SerializedPacket(buf)
     }
-    #[allow(dead_code)] // this function is just for testing
-    fn deserialize(packet: &SerializedPacket) -> Result<Self> {
+    pub fn deserialize(packet: &SerializedPacket) -> Result<Self, FanError> {
         let buf = packet.0;
         if buf[0..3] != PACKET_HEADER {
-            bail!("Packet header does not match!");
+            return Err(FanError::InvalidArgument(
+                "Packet header does not match!".to_owned(),
+            ));
         }
 
         Ok(Self {
@@ -245,6 +757,18 @@ impl PacketData {
     }
 }
 
+// everything is allowed unless a list is configured
+fn is_allowed(cmd: Cmd, allowed_commands: Option<&[Cmd]>) -> bool {
+    allowed_commands.is_none_or(|allowed_commands| allowed_commands.contains(&cmd))
+}
+
+fn direction_arg(direction: FanDirection) -> u8 {
+    match direction {
+        FanDirection::Forward => 0,
+        FanDirection::Reverse => 1,
+    }
+}
+
 fn whiten<const N: usize>(buffer: &[u8; N], seed: u8) -> [u8; N] {
     let salt = (PACKET_HEADER[1] & 0x3) << 5;
     let mut result = [0u8; N];
@@ -255,25 +779,16 @@ fn whiten<const N: usize>(buffer: &[u8; N], seed: u8) -> [u8; N] {
     result
 }
 
-fn sign(buffer: &[u8], tx_count: u8, seed: u16) -> u16 {
-    let key = [
-        (seed & 0xFF) as u8,
-        (seed >> 8) as u8,
-        tx_count,
-        0x0D,
-        0xBF,
-        0xE6,
-        0x42,
-        0x68,
-        0x41,
-        0x99,
-        0x2D,
-        0x0F,
-        0xB0,
-        0x54,
-        0xBB,
-        0x16,
-    ];
+// the part of the signing key after the seed and tx_count, which differs between device types
+pub const DEFAULT_SIGN_KEY_TAIL: [u8; 13] = [
+    0x0D, 0xBF, 0xE6, 0x42, 0x68, 0x41, 0x99, 0x2D, 0x0F, 0xB0, 0x54, 0xBB, 0x16,
+];
+
+fn sign(buffer: &[u8], tx_count: u8, seed: u16, key_tail: &[u8; 13]) -> u16 {
+    let mut key = [0u8; 16];
+    key[0..2].copy_from_slice(&seed.to_le_bytes());
+    key[2] = tx_count;
+    key[3..].copy_from_slice(key_tail);
 
     let mut block = GenericArray::from([0u8; 16]);
     block.copy_from_slice(&buffer[0..16]);
@@ -285,14 +800,65 @@ fn sign(buffer: &[u8], tx_count: u8, seed: u16) -> u16 {
     if sign != 0 { sign } else { 0xFFFF }
 }
 
-fn encrypt(decoded: &SerializedPacket) -> EncryptedPacket {
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeepaliveKind {
+    #[default]
+    Pair,
+    // re-send the light's cached state instead
+    Reassert,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LightKind {
+    Single,
+    // separate warm and cool channels
+    #[default]
+    Dual,
+}
+
+// Different remotes whiten and sign packets differently, so the last encoding step is
+// picked per fan. Only the FanLampPro V2 scheme is implemented so far.
+pub trait FanEncoder: Sync {
+    fn encrypt(&self, decoded: &SerializedPacket) -> EncryptedPacket;
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderKind {
+    #[default]
+    FanlampV2,
+}
+
+impl EncoderKind {
+    pub fn encoder(self, fan_config: &FanConfig) -> Box<dyn FanEncoder> {
+        match self {
+            Self::FanlampV2 => Box::new(FanlampV2Encoder {
+                sign_key_tail: fan_config.sign_key_tail,
+            }),
+        }
+    }
+}
+
+pub struct FanlampV2Encoder {
+    pub sign_key_tail: [u8; 13],
+}
+
+impl FanEncoder for FanlampV2Encoder {
+    fn encrypt(&self, decoded: &SerializedPacket) -> EncryptedPacket {
+        encrypt(decoded, &self.sign_key_tail)
+    }
+}
+
+fn encrypt(decoded: &SerializedPacket, sign_key_tail: &[u8; 13]) -> EncryptedPacket {
     let buf = decoded.0;
     let seed = u16::from_le_bytes([buf[PACKET_LEN - 2], buf[PACKET_LEN - 1]]);
 
     let mut msg_buf = [0u8; PACKET_LEN + 1];
     msg_buf[..(PACKET_LEN - 2)].copy_from_slice(&buf[..(PACKET_LEN - 2)]);
 
-    let sign = sign(&msg_buf[1..17], msg_buf[3], seed);
+    let sign = sign(&msg_buf[1..17], msg_buf[3], seed, sign_key_tail);
     msg_buf[PACKET_LEN - 2..PACKET_LEN].copy_from_slice(&sign.to_le_bytes());
     msg_buf[PACKET_LEN] = 0;
 
@@ -314,41 +880,734 @@ fn encrypt(decoded: &SerializedPacket) -> EncryptedPacket {
     EncryptedPacket(result)
 }
 
+// Undoes encrypt, for packets picked up while scanning. The signature isn't checked since
+// that needs the fan's key, the CRC is enough to tell whether it's one of these packets.
+pub fn decrypt(encrypted: &EncryptedPacket) -> Option<SerializedPacket> {
+    let buf = encrypted.0;
+    if buf[..2] != FRAME_HEADER {
+        return None;
+    }
+
+    let seed = u16::from_le_bytes([buf[PACKET_LEN + 3], buf[PACKET_LEN + 4]]);
+
+    let crc = Crc::<u16>::new(&CRC_16_XMODEM);
+    let mut digest = crc.digest_with_initial(!seed);
+    digest.update(&buf[FRAME_HEADER.len()..PACKET_LEN + 5]);
+    if digest.finalize().to_le_bytes() != buf[PACKET_LEN + 5..PACKET_LEN + 7] {
+        return None;
+    }
+
+    let mut result = [0u8; PACKET_LEN];
+    result[..2].copy_from_slice(&buf[2..4]);
+
+    // whitening is a plain xor, so applying it again reverses it
+    let unwhitened =
+        whiten::<{ PACKET_LEN - 1 }>(&buf[4..PACKET_LEN + 3].try_into().unwrap(), seed as u8);
+    result[2..PACKET_LEN - 2].copy_from_slice(&unwhitened[..PACKET_LEN - 4]);
+    result[PACKET_LEN - 2..].copy_from_slice(&seed.to_le_bytes());
+
+    Some(SerializedPacket(result))
+}
+
+// Brightness and color temperature end up in the same packet, so when a burst of them is
+// queued (e.g. someone dragging a slider) only the most recent of each is worth sending.
+// Relative updates build on the ones before them, so only an absolute update can replace
+// what came before it.
+pub fn coalesce_updates(updates: Vec<AttributeUpdate>) -> Vec<AttributeUpdate> {
+    let mut coalesced: Vec<AttributeUpdate> = Vec::with_capacity(updates.len());
+
+    for update in updates.into_iter().rev() {
+        let superseded = matches!(
+            update,
+            AttributeUpdate::Brightness(_) | AttributeUpdate::ColorTemp(_)
+        ) && coalesced.iter().any(|newer| {
+            mem::discriminant(newer) == mem::discriminant(&update)
+                && matches!(
+                    newer,
+                    AttributeUpdate::Brightness(NumericUpdate::Absolute(_))
+                        | AttributeUpdate::ColorTemp(NumericUpdate::Absolute(_))
+                )
+        });
+
+        if !superseded {
+            coalesced.push(update);
+        }
+    }
+
+    coalesced.reverse();
+
+    // There is no combined direction and speed command, so when both are queued the
+    // direction always goes first. Otherwise the new speed briefly runs the old direction.
+    coalesced.sort_by_key(|update| matches!(update, AttributeUpdate::FanSpeed(_)));
+
+    coalesced
+}
+
 pub async fn send_update_to_fan(
     update: AttributeUpdate,
     fan_state: &mut CachedFanState,
-    hci_socket: &HciSocket,
-) -> Result<()> {
-    let packets = PacketData::from_command(&update, fan_state);
-
-    for packet in packets {
-        send_packet_to_fan(packet, hci_socket).await?;
-    }
+    fan_config: &FanConfig,
+    config: &Config,
+    adapter: &BleAdapter,
+) -> Result<(), FanError> {
+    // one step per advertisement fits the fade into roughly fade_ms
+    let step_ms = config.intermediate_advertise_duration_ms() + config.inter_packet_delay_ms;
+    let fade_steps = (fan_config.fade_ms / step_ms.max(1)) as u32;
+    let packets = PacketData::from_command(
+        &update,
+        fan_state,
+        fan_config,
+        config.allowed_commands.as_deref(),
+        fade_steps,
+    );
 
-    Ok(())
+    send_packets_to_fan(packets, fan_state, fan_config, config, adapter).await
 }
 
 pub async fn send_keepalive_to_fan(
     fan_state: &mut CachedFanState,
-    hci_socket: &HciSocket,
-) -> Result<()> {
-    let packet = PacketData::new(
-        fan_state.tx_count,
-        fan_state.remote_uid,
-        Cmd::Pair,
-        [0, 0, 0],
+    fan_config: &FanConfig,
+    config: &Config,
+    adapter: &BleAdapter,
+) -> Result<(), FanError> {
+    // re-sending what the light is already doing doesn't visibly change anything, but that
+    // is only known once power has been commanded
+    let reassert = match (fan_config.keepalive_kind, fan_state.power) {
+        (KeepaliveKind::Pair, _) | (KeepaliveKind::Reassert, None) => None,
+        (KeepaliveKind::Reassert, Some(false)) => Some((Cmd::LightOff, [0, 0, 0])),
+        (KeepaliveKind::Reassert, Some(true)) => PacketData::light_args(fan_state, fan_config)
+            .map(|args| (Cmd::LightBrightnessTemperature, args)),
+    };
+    let (cmd, args) = reassert.unwrap_or((Cmd::Pair, [0, 0, 0]));
+
+    let packets = PacketData::next_allowed(
+        fan_state,
+        fan_config,
+        config.allowed_commands.as_deref(),
+        cmd,
+        args,
     );
-    fan_state.tx_count = fan_state.tx_count.wrapping_add(1);
 
-    send_packet_to_fan(packet, hci_socket).await
+    send_packets_to_fan(packets, fan_state, fan_config, config, adapter).await
+}
+
+// Re-sends the whole cached state so the fan catches up on anything it missed.
+// Anything that was never commanded is left alone.
+pub async fn resync_fan(
+    fan_state: &mut CachedFanState,
+    fan_config: &FanConfig,
+    config: &Config,
+    adapter: &BleAdapter,
+) -> Result<(), FanError> {
+    let mut packets = Vec::new();
+
+    if let Some(power) = fan_state.power {
+        packets.extend(PacketData::next_allowed(
+            fan_state,
+            fan_config,
+            config.allowed_commands.as_deref(),
+            match power {
+                true => Cmd::LightOn,
+                false => Cmd::LightOff,
+            },
+            [0, 0, 0],
+        ));
+    }
+
+    if fan_state.power != Some(false)
+        && let Some(args) = PacketData::light_args(fan_state, fan_config)
+    {
+        packets.extend(PacketData::next_allowed(
+            fan_state,
+            fan_config,
+            config.allowed_commands.as_deref(),
+            Cmd::LightBrightnessTemperature,
+            args,
+        ));
+    }
+
+    if let Some(direction) = fan_state.direction {
+        packets.extend(PacketData::next_allowed(
+            fan_state,
+            fan_config,
+            config.allowed_commands.as_deref(),
+            Cmd::Direction,
+            [direction_arg(direction), 0, 0],
+        ));
+    }
+
+    if let Some(speed) = fan_state.speed {
+        packets.extend(PacketData::next_allowed(
+            fan_state,
+            fan_config,
+            config.allowed_commands.as_deref(),
+            Cmd::FanSpeed,
+            [32, speed, 0],
+        ));
+    }
+
+    send_packets_to_fan(packets, fan_state, fan_config, config, adapter).await
+}
+
+// Only the last packet of a command gets the full advertise duration, the ones before it
+// can be sent quicker since the fan doesn't need to be left in any particular state.
+async fn send_packets_to_fan(
+    packets: Vec<PacketData>,
+    fan_state: &mut CachedFanState,
+    fan_config: &FanConfig,
+    config: &Config,
+    adapter: &BleAdapter,
+) -> Result<(), FanError> {
+    let count = packets.len();
+    let mut sendable = Vec::with_capacity(count);
+    let mut encoded = Vec::with_capacity(count);
+    for (i, packet) in packets.iter().enumerate() {
+        let wrapped = encode_packet(packet, fan_config, config);
+
+        let duration_ms = match i + 1 == count {
+            true => config.advertise_duration_ms,
+            false => config.intermediate_advertise_duration_ms(),
+        };
+        sendable.push(packet);
+        encoded.push((wrapped, Duration::from_millis(duration_ms)));
+    }
+
+    if config.dry_run || encoded.is_empty() {
+        return Ok(());
+    }
+
+    // a command right after a keepalive (or the other way around) can get one of them dropped
+    let min_gap = Duration::from_millis(config.min_send_gap_ms);
+    if let Some(last_sent) = fan_state.last_sent {
+        let elapsed = last_sent.elapsed();
+        if elapsed < min_gap {
+            sleep(min_gap.saturating_sub(elapsed)).await;
+        }
+    }
+
+    // swapping the data while advertising stays enabled gets the whole command out quicker
+    let result = if config.batch_packets {
+        let tx_counts: Vec<_> = sendable
+            .iter()
+            .map(|packet| (packet.uid, packet.tx_count))
+            .collect();
+        let result = advertise_packets(encoded, config, adapter)
+            .instrument(tracing::info_span!("packets", tx_counts = ?tx_counts))
+            .await;
+        for packet in sendable {
+            fan_state.record_sent(packet, result.is_ok(), config);
+        }
+        result
+    } else {
+        let mut result = Ok(());
+        for (i, (packet, encoded)) in sendable.into_iter().zip(encoded).enumerate() {
+            if i != 0 {
+                sleep(Duration::from_millis(config.inter_packet_delay_ms)).await;
+            }
+
+            result = advertise_packets(vec![encoded], config, adapter)
+                .instrument(tracing::info_span!(
+                    "packet",
+                    uid = packet.uid,
+                    tx_count = packet.tx_count
+                ))
+                .await;
+            fan_state.record_sent(packet, result.is_ok(), config);
+            if result.is_err() {
+                break;
+            }
+        }
+        result
+    };
+
+    fan_state.last_sent = Some(Instant::now());
+
+    result
 }
 
-async fn send_packet_to_fan(packet: PacketData, hci_socket: &HciSocket) -> Result<()> {
+fn encode_packet(packet: &PacketData, fan_config: &FanConfig, config: &Config) -> WrappedPacket {
     log::debug!("sending packet: {packet:?}");
 
     let serialized = packet.serialize();
-    let encrypted = encrypt(&serialized);
+    let encrypted = fan_config.encoder.encoder(fan_config).encrypt(&serialized);
     let wrapped = wrap_packet(&encrypted);
 
-    advertise_ble_message(hci_socket, &wrapped).await
+    // each stage is logged so it's easy to see where a divergence from ha-ble-adv starts
+    let level = match config.dry_run {
+        true => log::Level::Info,
+        false => log::Level::Debug,
+    };
+    log::log!(
+        level,
+        "packet bytes (tx_count {}):\n  serialized: {}\n  encrypted:  {}\n  wrapped:    {}",
+        packet.tx_count,
+        to_hex(&serialized.0),
+        to_hex(&encrypted.0),
+        to_hex(&wrapped.0)
+    );
+
+    if config.dry_run {
+        log::info!("dry run, not sending packet (tx_count {})", packet.tx_count);
+    }
+
+    wrapped
+}
+
+async fn advertise_packets(
+    packets: Vec<(WrappedPacket, Duration)>,
+    config: &Config,
+    adapter: &BleAdapter,
+) -> Result<(), FanError> {
+    // the timeout is per packet, a batch gets it for each one it carries
+    let send_timeout = Duration::from_millis(config.send_timeout_ms) * packets.len() as u32;
+
+    // the retries reuse the same tx_count, so the fan can't act on the packet twice
+    let started = Instant::now();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        let result = match adapter.advertise_batch(&packets, send_timeout).await {
+            Ok(result) => result.map_err(FanError::BleSend),
+            Err(_) => Err(FanError::Timeout),
+        };
+
+        let elapsed = started.elapsed();
+        if result.is_ok() || attempt > config.send_retries {
+            let elapsed_ms = elapsed.as_millis() as u64;
+            metrics::LAST_SEND_DURATION_MS.store(elapsed_ms, Ordering::Relaxed);
+            metrics::SEND_DURATION_MS.fetch_add(elapsed_ms, Ordering::Relaxed);
+        }
+
+        match result {
+            Ok(()) => {
+                metrics::PACKETS_SENT.fetch_add(packets.len() as u64, Ordering::Relaxed);
+                log::debug!(
+                    "advertised {} packet(s) in {elapsed:?} after {attempt} attempt(s)",
+                    packets.len()
+                );
+                return Ok(());
+            }
+            Err(err) => {
+                metrics::BLE_SEND_ERRORS.fetch_add(1, Ordering::Relaxed);
+                if attempt > config.send_retries {
+                    return Err(err);
+                }
+                metrics::SEND_RETRIES.fetch_add(1, Ordering::Relaxed);
+
+                log::warn!(
+                    "{:?}",
+                    anyhow::Error::from(err).context(format!(
+                        "attempt {attempt} to send packets failed, retrying"
+                    ))
+                );
+            }
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> PacketData {
+        PacketData {
+            tx_count: 16,
+            device_type: DEFAULT_DEVICE_TYPE,
+            uid: 0x12345678,
+            index: DEFAULT_INDEX,
+            cmd: Cmd::LightOn as u8,
+            arg0: 0,
+            arg1: 0,
+            arg2: 0,
+            seed: DEFAULT_SEED,
+        }
+    }
+
+    // catches accidental changes to XOR_LUT, the sign key or the framing
+    #[test]
+    fn wrapped_packet_matches_known_bytes() {
+        let wrapped = wrap_packet(&encrypt(&packet().serialize(), &DEFAULT_SIGN_KEY_TAIL));
+
+        assert_eq!(
+            wrapped.0,
+            [
+                0x02, 0x01, 0x19, 0x1B, 0x03, 0xF0, 0x08, 0x20, 0x82, 0x36, 0x39, 0xFD, 0x5F, 0xCB,
+                0x37, 0x5D, 0x4B, 0x1A, 0x45, 0x77, 0x0F, 0x91, 0x80, 0xFF, 0x7C, 0x2E, 0xC6, 0x53,
+                0x2B, 0xB8, 0x33,
+            ]
+        );
+    }
+
+    #[test]
+    fn decrypt_reverses_encrypt() {
+        let encrypted = encrypt(&packet().serialize(), &DEFAULT_SIGN_KEY_TAIL);
+        let decrypted = decrypt(&encrypted).expect("CRC should match");
+        let decoded = PacketData::deserialize(&decrypted).unwrap();
+
+        assert_eq!(decoded.tx_count, 16);
+        assert_eq!(decoded.device_type, DEFAULT_DEVICE_TYPE);
+        assert_eq!(decoded.uid, 0x12345678);
+        assert_eq!(decoded.index, DEFAULT_INDEX);
+        assert_eq!(decoded.cmd, Cmd::LightOn as u8);
+        assert_eq!([decoded.arg0, decoded.arg1, decoded.arg2], [0, 0, 0]);
+        assert_eq!(decoded.seed, DEFAULT_SEED);
+    }
+
+    // bit by bit, so it doesn't share anything with the crc crate
+    fn crc16_xmodem(data: &[u8], initial: u16) -> u16 {
+        let mut crc = initial;
+        for &byte in data {
+            crc ^= (byte as u16) << 8;
+            for _ in 0..8 {
+                crc = match crc & 0x8000 {
+                    0 => crc << 1,
+                    _ => (crc << 1) ^ 0x1021,
+                };
+            }
+        }
+        crc
+    }
+
+    #[test]
+    fn crc_matches_independent_implementation() {
+        let encrypted = encrypt(&packet().serialize(), &DEFAULT_SIGN_KEY_TAIL).0;
+        let crc = crc16_xmodem(
+            &encrypted[FRAME_HEADER.len()..PACKET_LEN + 5],
+            !DEFAULT_SEED,
+        );
+
+        assert_eq!(crc, 0x33B8);
+        assert_eq!(encrypted[PACKET_LEN + 5..], crc.to_le_bytes());
+    }
+
+    #[test]
+    fn crc_detects_flipped_payload_byte() {
+        let mut encrypted = encrypt(&packet().serialize(), &DEFAULT_SIGN_KEY_TAIL);
+        encrypted.0[10] ^= 0x01;
+
+        let crc = crc16_xmodem(
+            &encrypted.0[FRAME_HEADER.len()..PACKET_LEN + 5],
+            !DEFAULT_SEED,
+        );
+        assert_ne!(encrypted.0[PACKET_LEN + 5..], crc.to_le_bytes());
+        assert!(decrypt(&encrypted).is_none());
+    }
+
+    #[test]
+    fn deserialize_reverses_serialize() {
+        let packet = PacketData {
+            tx_count: 0xFE,
+            device_type: 0xBEEF,
+            uid: 0xDEADBEEF,
+            index: 3,
+            cmd: Cmd::LightBrightnessTemperature as u8,
+            arg0: 1,
+            arg1: 2,
+            arg2: 3,
+            seed: 0xCAFE,
+        };
+
+        assert_eq!(
+            PacketData::deserialize(&packet.serialize()).unwrap(),
+            packet
+        );
+    }
+
+    #[test]
+    fn speed_from_percentage_rounds_half_up() {
+        let props = NumericProperties {
+            min: 0,
+            max: 6,
+            step: 1,
+        };
+
+        assert_eq!(speed_from_percentage(0, &props), 0);
+        assert_eq!(speed_from_percentage(16, &props), 1);
+        assert_eq!(speed_from_percentage(50, &props), 3);
+        assert_eq!(speed_from_percentage(83, &props), 5);
+        assert_eq!(speed_from_percentage(100, &props), 6);
+    }
+
+    #[test]
+    fn speed_from_percentage_uses_middle_speed() {
+        let props = NumericProperties {
+            min: 0,
+            max: 3,
+            step: 1,
+        };
+
+        assert_eq!(speed_from_percentage(50, &props), 2);
+        assert_eq!(speed_from_percentage(150, &props), 3);
+    }
+
+    fn fan_config() -> FanConfig {
+        serde_json::from_value(serde_json::json!({
+            "device_id": "fan",
+            "remote_uid": 0x12345678,
+        }))
+        .unwrap()
+    }
+
+    fn cmds(packets: &[PacketData]) -> Vec<u8> {
+        packets.iter().map(|packet| packet.cmd).collect()
+    }
+
+    #[test]
+    fn power_on_is_sent_before_light() {
+        let fan_config = fan_config();
+        let mut fan_state = CachedFanState::new(&fan_config);
+        fan_state.brightness = Some(128);
+
+        let packets = PacketData::from_command(
+            &AttributeUpdate::Power(true),
+            &mut fan_state,
+            &fan_config,
+            None,
+            0,
+        );
+
+        assert_eq!(
+            cmds(&packets),
+            [Cmd::LightOn as u8, Cmd::LightBrightnessTemperature as u8]
+        );
+    }
+
+    #[test]
+    fn power_on_last_sends_light_first() {
+        let mut fan_config = fan_config();
+        fan_config.power_on_last = true;
+        let mut fan_state = CachedFanState::new(&fan_config);
+        fan_state.power = Some(false);
+        fan_state.brightness = Some(0);
+
+        let packets = PacketData::from_command(
+            &AttributeUpdate::Brightness(NumericUpdate::Absolute(128)),
+            &mut fan_state,
+            &fan_config,
+            None,
+            0,
+        );
+
+        assert_eq!(
+            cmds(&packets),
+            [Cmd::LightBrightnessTemperature as u8, Cmd::LightOn as u8]
+        );
+    }
+
+    #[test]
+    fn speed_is_sent_last() {
+        let fan_config = fan_config();
+        let mut fan_state = CachedFanState::new(&fan_config);
+        fan_state.brightness = Some(128);
+
+        let updates = coalesce_updates(vec![
+            AttributeUpdate::FanSpeed(NumericUpdate::Absolute(3)),
+            AttributeUpdate::FanDirection(FanDirection::Reverse),
+            AttributeUpdate::Power(true),
+        ]);
+        let packets: Vec<_> = updates
+            .iter()
+            .flat_map(|update| {
+                PacketData::from_command(update, &mut fan_state, &fan_config, None, 0)
+            })
+            .collect();
+
+        assert_eq!(
+            cmds(&packets),
+            [
+                Cmd::Direction as u8,
+                Cmd::LightOn as u8,
+                Cmd::LightBrightnessTemperature as u8,
+                Cmd::FanSpeed as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn coalesce_keeps_relative_updates() {
+        let updates = coalesce_updates(vec![
+            AttributeUpdate::Brightness(NumericUpdate::Absolute(10)),
+            AttributeUpdate::Brightness(NumericUpdate::Absolute(20)),
+            AttributeUpdate::Brightness(NumericUpdate::Relative(5)),
+            AttributeUpdate::Brightness(NumericUpdate::Relative(5)),
+        ]);
+
+        assert!(matches!(
+            updates.as_slice(),
+            [
+                AttributeUpdate::Brightness(NumericUpdate::Absolute(20)),
+                AttributeUpdate::Brightness(NumericUpdate::Relative(5)),
+                AttributeUpdate::Brightness(NumericUpdate::Relative(5)),
+            ]
+        ));
+    }
+
+    #[test]
+    fn observed_light_on_raises_zero_brightness() {
+        let fan_config = fan_config();
+        let mut fan_state = CachedFanState::new(&fan_config);
+        fan_state.power = Some(false);
+        fan_state.brightness = Some(0);
+
+        let packet = PacketData::new(
+            fan_config.initial_tx_count,
+            0x12345678,
+            &fan_config,
+            Cmd::LightOn,
+            [0, 0, 0],
+        );
+
+        assert!(fan_state.observe(&packet, &fan_config));
+        assert_eq!(fan_state.power, Some(true));
+        assert_eq!(fan_state.brightness, Some(255));
+        fan_state.normalize(&fan_config);
+    }
+
+    #[test]
+    fn disallowed_update_changes_nothing() {
+        let fan_config = fan_config();
+        let mut fan_state = CachedFanState::new(&fan_config);
+
+        let packets = PacketData::from_command(
+            &AttributeUpdate::FanDirection(FanDirection::Reverse),
+            &mut fan_state,
+            &fan_config,
+            Some(&[Cmd::LightOn, Cmd::LightOff]),
+            0,
+        );
+
+        assert!(packets.is_empty());
+        assert_eq!(fan_state.direction, None);
+        assert_eq!(
+            fan_state.remotes,
+            [(0x12345678, fan_config.initial_tx_count)]
+        );
+    }
+
+    #[test]
+    fn brightness_needing_disallowed_power_changes_nothing() {
+        let fan_config = fan_config();
+        let mut fan_state = CachedFanState::new(&fan_config);
+
+        let packets = PacketData::from_command(
+            &AttributeUpdate::Brightness(NumericUpdate::Absolute(100)),
+            &mut fan_state,
+            &fan_config,
+            Some(&[Cmd::LightBrightnessTemperature]),
+            0,
+        );
+
+        assert!(packets.is_empty());
+        assert_eq!(fan_state.power, None);
+        assert_eq!(fan_state.brightness, None);
+    }
+
+    fn config() -> Config {
+        use base64ct::{Base64, Encoding};
+        use p256::pkcs8::{EncodePrivateKey, EncodePublicKey};
+
+        let private_key = p256::ecdsa::SigningKey::random(&mut rand_core::OsRng);
+        let public_key = private_key.verifying_key().to_public_key_der().unwrap();
+
+        serde_json::from_value(serde_json::json!({
+            "device_id": "controller",
+            "server_addr": "127.0.0.1:8894",
+            "server_public_key": Base64::encode_string(public_key.as_bytes()),
+            "private_key": Base64::encode_string(private_key.to_pkcs8_der().unwrap().as_bytes()),
+            "hci_device": 0,
+            "advertise_duration_ms": 0,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn brightness_update_sends_expected_hci_commands() {
+        let config = config();
+        let fan_config = fan_config();
+        let mut fan_state = CachedFanState::new(&fan_config);
+        fan_state.power = Some(true);
+        fan_state.brightness = Some(100);
+        let (adapter, controller) = BleAdapter::fake(&config);
+
+        send_update_to_fan(
+            AttributeUpdate::Brightness(NumericUpdate::Absolute(200)),
+            &mut fan_state,
+            &fan_config,
+            &config,
+            &adapter,
+        )
+        .await
+        .unwrap();
+
+        let packet = PacketData::new(
+            fan_config.initial_tx_count,
+            0x12345678,
+            &fan_config,
+            Cmd::LightBrightnessTemperature,
+            [0, 200, 200],
+        );
+        let wrapped = encode_packet(&packet, &fan_config, &config);
+        let mut set_data = vec![0x01, 0x08, 0x20, 32, 31];
+        set_data.extend(wrapped.0);
+
+        assert_eq!(
+            controller.take_commands(),
+            [
+                vec![0x01, 0x0A, 0x20, 1, 0],
+                vec![
+                    0x01, 0x06, 0x20, 15, 0x20, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x07, 0
+                ],
+                set_data,
+                vec![0x01, 0x0A, 0x20, 1, 1],
+                vec![0x01, 0x0A, 0x20, 1, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn uncoupled_brightness_needs_light_on() {
+        let mut fan_config = fan_config();
+        fan_config.couple_brightness_power = false;
+
+        for power in [None, Some(false)] {
+            let mut fan_state = CachedFanState::new(&fan_config);
+            fan_state.power = power;
+            fan_state.brightness = Some(100);
+
+            let packets = PacketData::from_command(
+                &AttributeUpdate::Brightness(NumericUpdate::Absolute(0)),
+                &mut fan_state,
+                &fan_config,
+                None,
+                0,
+            );
+
+            assert!(packets.is_empty());
+            assert_eq!(fan_state.brightness, Some(0));
+        }
+    }
+
+    #[test]
+    fn kelvin_round_trips_through_cache() {
+        let mut fan_config = fan_config();
+        fan_config.color_temp_min_kelvin = Some(2700);
+        fan_config.color_temp_max_kelvin = Some(6500);
+
+        let cached = color_temp_to_cached(4190, &fan_config);
+        assert_eq!(color_temp_from_cached(cached, &fan_config), 4190);
+
+        for cached in 0..=255 {
+            let kelvin = color_temp_from_cached(cached, &fan_config);
+            assert_eq!(color_temp_to_cached(kelvin, &fan_config), cached);
+        }
+    }
 }
@@ -0,0 +1,7 @@
+// The packet building and BLE code, usable without the server connection in main.rs.
+
+pub mod ble;
+pub mod config;
+pub mod control;
+pub mod fan;
+pub mod metrics;
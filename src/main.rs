@@ -1,60 +1,184 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use devicectrl_common::{
-    DeviceState,
+    DeviceId, DeviceState,
     protocol::simple::{
         DeviceBoundSimpleMessage, ServerBoundSimpleMessage,
         tokio::{CryptoContext, TransportEvent, make_transport_channels, transport_task},
     },
+    updates::AttributeUpdate,
 };
-use hciraw::{HciChannel, HciSocket, HciSocketAddr};
 use sd_notify::NotifyState;
-use std::{env, path::PathBuf, time::Duration};
-use tokio::{sync::Mutex, time::sleep};
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    env, mem,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::{Mutex, mpsc, watch},
+    time::{interval, sleep, timeout},
+};
+use tracing::Instrument;
 use tracing_subscriber::{EnvFilter, filter::LevelFilter};
 
-use crate::fan::{CachedFanState, send_keepalive_to_fan, send_update_to_fan};
+use devicectrl_fan_controller::{
+    ble::{BleAdapter, scan},
+    config::{self, Config, FanConfig, StateReporting},
+    control,
+    fan::{
+        CachedFanState, PacketData, coalesce_updates, resync_fan, send_keepalive_to_fan,
+        send_update_to_fan,
+    },
+    metrics,
+};
+
+mod cli;
+
+// servers that poll aggressively get at most one answer per interval
+const STATE_QUERY_MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+// how often the main loop tells the watchdog task it's still alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+// how long shutdown waits for the offline notifications to be picked up by the transport
+const SHUTDOWN_NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Fan {
+    pub config: &'static FanConfig,
+    pub ble_adapter: &'static BleAdapter,
+    pub state: Mutex<CachedFanState>,
+    // only what scanning saw, kept apart from the state our own sends write to
+    pub confirmed: Option<Mutex<CachedFanState>>,
+    // what the main loop reports, so it never waits on a send holding the state
+    pub snapshot: watch::Sender<DeviceState>,
+    pub updates: mpsc::UnboundedSender<AttributeUpdate>,
+    pub consecutive_send_failures: AtomicU32,
+    // None when disabled, a reload changes them without restarting the tasks
+    pub keepalive_interval: watch::Sender<Option<Duration>>,
+    pub resync_interval: watch::Sender<Option<Duration>>,
+}
+
+impl Fan {
+    fn record_send_result(&self, result: &Result<()>) {
+        match result {
+            Ok(()) => self.consecutive_send_failures.store(0, Ordering::Relaxed),
+            Err(_) => {
+                self.consecutive_send_failures
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    fn publish(&self, fan_state: &CachedFanState) {
+        self.snapshot
+            .send_replace(fan_state.to_device_state(self.config));
+    }
+    fn state_notification(&self, device_id: DeviceId, config: &Config) -> ServerBoundSimpleMessage {
+        self.notification(device_id, self.reachable(config))
+    }
+    // sent when the controller goes away, so the server doesn't keep showing stale fans
+    fn offline_notification(&self, device_id: DeviceId) -> ServerBoundSimpleMessage {
+        self.notification(device_id, false)
+    }
+    fn notification(&self, device_id: DeviceId, reachable: bool) -> ServerBoundSimpleMessage {
+        let new_state = self.snapshot.borrow().clone();
 
-mod ble;
-mod config;
-mod fan;
+        ServerBoundSimpleMessage::UpdateNotification(devicectrl_common::UpdateNotification {
+            device_id,
+            reachable,
+            new_state,
+        })
+    }
+    // we can't hear back from the fan, so a run of failed sends is the best sign it's gone
+    fn reachable(&self, config: &Config) -> bool {
+        config.unreachable_after_failures == 0
+            || self.consecutive_send_failures.load(Ordering::Relaxed)
+                < config.unreachable_after_failures
+    }
+}
 
 struct AppState {
-    pub hci_socket: HciSocket,
-    pub fan_state: Mutex<CachedFanState>,
+    pub fans: HashMap<DeviceId, Fan>,
+}
+
+// takes the same directives as RUST_LOG, so single modules can be made more verbose
+fn log_filter(directives: &str) -> Result<EnvFilter> {
+    Ok(EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse(directives)?)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .without_time() // systemd logs already include timestamps
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .with_env_var("LOG_LEVEL")
-                .from_env()?,
-        )
-        .init();
-
-    let config = Box::leak(Box::new(
-        config::load_config(&PathBuf::from(
-            env::var("CONFIG_PATH").expect("CONFIG_PATH env var missing!"),
-        ))
-        .await
-        .context("failed to load config")?,
+        .with_env_filter(log_filter(&env::var("LOG_LEVEL").unwrap_or_default())?);
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber.json().init(),
+        Ok("text") | Err(_) => subscriber.init(),
+        Ok(other) => bail!("unknown LOG_FORMAT {other:?}, expected text or json"),
+    }
+
+    let mut args = env::args().skip(1);
+    let subcommand = args.next();
+
+    // there is no config yet to load
+    if subcommand.as_deref() == Some("init") {
+        return cli::init(args);
+    }
+
+    let config_path = PathBuf::from(env::var("CONFIG_PATH").expect("CONFIG_PATH env var missing!"));
+
+    let config: &Config = Box::leak(Box::new(
+        config::load_config(&config_path)
+            .await
+            .context("failed to load config")?,
     ));
 
-    let app_state: &AppState = Box::leak(Box::new(AppState {
-        hci_socket: HciSocket::bind(HciSocketAddr::new(Some(config.hci_device), HciChannel::Raw))?,
-        fan_state: Mutex::new(CachedFanState {
-            tx_count: 16, // this is what FanLampPro app initializes with
-            power: true,
-            color_temp: 0,
-            brightness: 255,
-            speed: 0,
+    match subcommand.as_deref() {
+        Some("send") => return cli::send(config, args).await,
+        Some(other) => bail!("unknown subcommand {other:?}"),
+        None => {}
+    }
 
-            remote_uid: config.remote_uid,
-        }),
-    }));
+    let mut ble_adapters = HashMap::new();
+    for fan_config in &config.fans {
+        let hci_device = fan_config.hci_device.unwrap_or(config.hci_device);
+        if let Entry::Vacant(entry) = ble_adapters.entry(hci_device) {
+            entry.insert(BleAdapter::open(hci_device, config)?);
+        }
+    }
+    let ble_adapters: &HashMap<u16, BleAdapter> = Box::leak(Box::new(ble_adapters));
+
+    let mut fans = HashMap::new();
+    let mut update_receivers = Vec::new();
+    for fan_config in &config.fans {
+        let (update_tx, update_rx) = mpsc::unbounded_channel();
+
+        let fan = Fan {
+            config: fan_config,
+            ble_adapter: &ble_adapters[&fan_config.hci_device.unwrap_or(config.hci_device)],
+            state: Mutex::new(CachedFanState::new(fan_config)),
+            confirmed: (config.state_reporting == StateReporting::Confirmed)
+                .then(|| Mutex::new(CachedFanState::new(fan_config))),
+            snapshot: watch::Sender::new(DeviceState::Unknown),
+            updates: update_tx,
+            consecutive_send_failures: AtomicU32::new(0),
+            keepalive_interval: watch::Sender::new(keepalive_interval(fan_config)),
+            resync_interval: watch::Sender::new(resync_interval(fan_config)),
+        };
+
+        if fans.insert(fan_config.device_id, fan).is_some() {
+            bail!(
+                "fan {:?} is configured more than once",
+                fan_config.device_id
+            );
+        }
+        update_receivers.push((fan_config.device_id, update_rx));
+    }
+
+    let app_state: &AppState = Box::leak(Box::new(AppState { fans }));
 
     let (mut client_channels, worker_channels) = make_transport_channels(16);
 
@@ -70,55 +194,513 @@ async fn main() -> Result<()> {
         crypto,
     ));
 
-    // Sometimes the fan ignores commands when it has not received one for a while.
-    // I have not found anything documenting this, but sending a 'keepalive' seems to work. 🤷‍♂️
-    tokio::spawn({
-        async move {
-            loop {
-                sleep(Duration::from_secs(60 * 60)).await;
+    for (device_id, update_rx) in update_receivers {
+        let fan = &app_state.fans[&device_id];
 
-                let mut fan_state = app_state.fan_state.lock().await;
-                if let Err(err) = send_keepalive_to_fan(&mut fan_state, &app_state.hci_socket).await
-                {
-                    log::error!("{:?}", err.context("Failed to send keepalive to fan"));
-                }
-            }
+        // everything logged by the fan's tasks carries its device id
+        let span = tracing::info_span!("fan", device_id = ?device_id);
+
+        tokio::spawn(
+            update_worker(fan, config, update_rx, client_channels.outgoing.clone())
+                .instrument(span.clone()),
+        );
+        // started even when disabled, since a reload can turn them on
+        tokio::spawn(keepalive_task(fan, config).instrument(span.clone()));
+        tokio::spawn(resync_task(fan, config).instrument(span));
+    }
+
+    if config.scan && !config.dry_run {
+        for &hci_device in ble_adapters.keys() {
+            let packets = scan::start(hci_device).await?;
+            tokio::spawn(observe_task(
+                app_state,
+                config,
+                packets,
+                client_channels.outgoing.clone(),
+            ));
         }
-    });
+    }
+
+    if let Some(metrics_addr) = config.metrics_addr {
+        let fans = app_state
+            .fans
+            .iter()
+            .map(|(&device_id, fan)| metrics::FanHandle {
+                device_id,
+                state: &fan.state,
+            })
+            .collect();
+
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr, fans).await {
+                log::error!("{:?}", err.context("Metrics server has stopped"));
+            }
+        });
+    }
+
+    if let Some(control_addr) = config.control_addr {
+        let fans = app_state
+            .fans
+            .iter()
+            .map(|(&device_id, fan)| control::FanHandle {
+                device_id,
+                config: fan.config,
+                state: &fan.state,
+                updates: fan.updates.clone(),
+            })
+            .collect();
+
+        tokio::spawn(async move {
+            if let Err(err) = control::serve(control_addr, fans).await {
+                log::error!("{:?}", err.context("Control server has stopped"));
+            }
+        });
+    }
 
     let _ = sd_notify::notify(false, &[NotifyState::Ready]);
 
+    let (heartbeat_tx, heartbeat_rx) = watch::channel(Instant::now());
+    let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+
+    let mut watchdog_usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut watchdog_usec) {
+        tokio::spawn(watchdog_task(
+            heartbeat_rx,
+            Duration::from_micros(watchdog_usec),
+        ));
+    }
+
+    let mut last_state_responses: HashMap<DeviceId, Instant> = HashMap::new();
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+
     loop {
-        match client_channels
-            .incoming
-            .recv()
-            .await
-            .context("Failed to receive command")?
-        {
+        let event = tokio::select! {
+            event = client_channels.incoming.recv() => event.context("Failed to receive command")?,
+            _ = sigterm.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+            _ = heartbeat.tick() => {
+                let _ = heartbeat_tx.send(Instant::now());
+                continue;
+            }
+            // reloading waits on each fan's state, which a send can hold for a while, so it
+            // runs on its own instead of holding up the heartbeat
+            _ = sighup.recv() => {
+                let config_path = config_path.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = reload_config(&config_path, app_state, config).await {
+                        log::error!("{:?}", err.context("Failed to reload config"));
+                    }
+                });
+                continue;
+            }
+            // for when a fan was changed with its physical remote and is out of sync
+            _ = sigusr1.recv() => {
+                log::info!("Resending cached state to all fans");
+                for (&device_id, fan) in &app_state.fans {
+                    let span = tracing::info_span!("fan", device_id = ?device_id);
+                    tokio::spawn(resync(fan, config).instrument(span));
+                }
+                continue;
+            }
+        };
+
+        match event {
             TransportEvent::Connected => {
                 log::info!("Connected to server!");
+                metrics::SERVER_CONNECTS.fetch_add(1, Ordering::Relaxed);
+
+                // tell the server where things stand instead of waiting for it to ask
+                for (&device_id, fan) in &app_state.fans {
+                    client_channels
+                        .outgoing
+                        .send(fan.state_notification(device_id, config))
+                        .await?;
+                }
             }
             TransportEvent::Error(err) => {
                 log::error!("{:?}", err.context("failed to communicate with server"));
             }
             TransportEvent::Message(DeviceBoundSimpleMessage::UpdateCommand(update)) => {
-                // since this takes 500ms the recv() call above may lag when under pressure
-                let mut fan_state = app_state.fan_state.lock().await;
-                send_update_to_fan(update.update, &mut fan_state, &app_state.hci_socket).await?;
+                metrics::record_command();
+
+                let Some(fan) = app_state.fans.get(&update.device_id) else {
+                    log::warn!("ignoring update for unknown device {:?}", update.device_id);
+                    continue;
+                };
+
+                fan.updates
+                    .send(update.update)
+                    .context("Update worker has stopped")?;
             }
             TransportEvent::Message(DeviceBoundSimpleMessage::StateQuery { device_id }) => {
+                // answering would claim to be a device we don't control
+                let Some(fan) = app_state.fans.get(&device_id) else {
+                    log::warn!("ignoring state query for unknown device {device_id:?}");
+                    continue;
+                };
+
+                if last_state_responses
+                    .get(&device_id)
+                    .is_some_and(|last| last.elapsed() < STATE_QUERY_MIN_INTERVAL)
+                {
+                    log::debug!("ignoring state query, last one was answered too recently");
+                    continue;
+                }
+                last_state_responses.insert(device_id, Instant::now());
+
                 client_channels
                     .outgoing
-                    .send(ServerBoundSimpleMessage::UpdateNotification(
-                        devicectrl_common::UpdateNotification {
-                            device_id,
-                            reachable: true,
-                            new_state: DeviceState::Unknown,
-                        },
-                    ))
+                    .send(fan.state_notification(device_id, config))
                     .await?;
             }
             _ => {}
         }
     }
+
+    log::info!("Shutting down");
+    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
+
+    // the transport may already be gone, so this is best effort
+    let outgoing = &client_channels.outgoing;
+    let notified = timeout(SHUTDOWN_NOTIFY_TIMEOUT, async {
+        for (&device_id, fan) in &app_state.fans {
+            outgoing.send(fan.offline_notification(device_id)).await?;
+        }
+        while outgoing.capacity() < outgoing.max_capacity() {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        anyhow::Ok(())
+    })
+    .await;
+    if !matches!(notified, Ok(Ok(()))) {
+        log::warn!("Could not tell the server that the fans are going offline");
+    }
+
+    // don't leave the adapter advertising whatever it was sending when we got stopped
+    for ble_adapter in ble_adapters.values() {
+        if let Err(err) = ble_adapter.disable_advertising().await {
+            log::error!("{:?}", err.context("Failed to disable advertising"));
+        }
+    }
+
+    Ok(())
+}
+
+// Pings the systemd watchdog only while the main loop keeps checking in, so a wedged
+// controller gets restarted instead of sitting there looking alive.
+async fn watchdog_task(heartbeat_rx: watch::Receiver<Instant>, timeout: Duration) {
+    let mut ticker = interval(timeout / 2);
+
+    loop {
+        ticker.tick().await;
+
+        let since_heartbeat = heartbeat_rx.borrow().elapsed();
+        if since_heartbeat < timeout / 2 {
+            let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+        } else {
+            log::warn!(
+                "main loop has not checked in for {since_heartbeat:?}, skipping watchdog ping"
+            );
+        }
+    }
+}
+
+// Only the remote uids can be swapped while running, the connection to the server and the
+// per fan tasks are set up once at startup, so anything else is just reported.
+async fn reload_config(path: &Path, app_state: &AppState, config: &Config) -> Result<()> {
+    let new_config = config::load_config(path).await?;
+    log::info!("Reloading config");
+
+    if new_config.server_addr != config.server_addr
+        || new_config.server_public_key != config.server_public_key
+        || new_config.private_key.verifying_key() != config.private_key.verifying_key()
+    {
+        log::warn!("server address or keys changed, restart to apply");
+    }
+
+    for new_fan_config in &new_config.fans {
+        let Some(fan) = app_state.fans.get(&new_fan_config.device_id) else {
+            log::warn!(
+                "fan {:?} was added, restart to apply",
+                new_fan_config.device_id
+            );
+            continue;
+        };
+
+        let mut fan_state = fan.state.lock().await;
+        if !fan_state
+            .remotes
+            .iter()
+            .map(|(uid, _)| uid)
+            .eq(&new_fan_config.remote_uids)
+        {
+            log::info!(
+                "fan {:?} remote uids changed to {:#x?}",
+                new_fan_config.device_id,
+                new_fan_config.remote_uids
+            );
+
+            // remotes that are kept carry on with their tx_count
+            let old_remotes = mem::take(&mut fan_state.remotes);
+            fan_state.remotes = new_fan_config
+                .remote_uids
+                .iter()
+                .map(|&uid| {
+                    let tx_count = old_remotes
+                        .iter()
+                        .find(|(old_uid, _)| *old_uid == uid)
+                        .map_or(new_fan_config.initial_tx_count, |&(_, tx_count)| tx_count);
+                    (uid, tx_count)
+                })
+                .collect();
+        }
+
+        let new_keepalive_interval = keepalive_interval(new_fan_config);
+        if *fan.keepalive_interval.borrow() != new_keepalive_interval {
+            log::info!(
+                "fan {:?} keepalive interval changed to {new_keepalive_interval:?}",
+                new_fan_config.device_id
+            );
+            fan.keepalive_interval.send_replace(new_keepalive_interval);
+        }
+
+        let new_resync_interval = resync_interval(new_fan_config);
+        if *fan.resync_interval.borrow() != new_resync_interval {
+            log::info!(
+                "fan {:?} resync interval changed to {new_resync_interval:?}",
+                new_fan_config.device_id
+            );
+            fan.resync_interval.send_replace(new_resync_interval);
+        }
+    }
+
+    Ok(())
+}
+
+// advertising takes a while per packet, so it happens here instead of in the receive loop
+async fn update_worker(
+    fan: &'static Fan,
+    config: &'static Config,
+    mut update_rx: mpsc::UnboundedReceiver<AttributeUpdate>,
+    outgoing: mpsc::Sender<ServerBoundSimpleMessage>,
+) {
+    while let Some(update) = update_rx.recv().await {
+        sleep(Duration::from_millis(config.coalesce_window_ms)).await;
+
+        let mut updates = vec![update];
+        while let Ok(update) = update_rx.try_recv() {
+            updates.push(update);
+        }
+
+        let received = updates.len();
+        let updates = coalesce_updates(updates);
+        if updates.len() != received {
+            log::debug!("coalesced {received} queued updates into {}", updates.len());
+        }
+
+        let was_reachable = fan.reachable(config);
+        let mut any_sent = false;
+        let mut fan_state = fan.state.lock().await;
+        for update in updates {
+            let result =
+                send_update_to_fan(update, &mut fan_state, fan.config, config, fan.ble_adapter)
+                    .await
+                    .map_err(anyhow::Error::from);
+            fan.record_send_result(&result);
+
+            match result {
+                Ok(()) => any_sent = true,
+                Err(err) => log::error!("{:?}", err.context("Failed to send update to fan")),
+            }
+        }
+        // otherwise observe_task publishes the confirmed state
+        let optimistic = fan.confirmed.is_none();
+        if optimistic {
+            fan.publish(&fan_state);
+        }
+        drop(fan_state);
+
+        // the server shouldn't have to ask to find out what it just changed,
+        // or that the fan stopped responding
+        if (optimistic && any_sent) || fan.reachable(config) != was_reachable {
+            let notification = fan.state_notification(fan.config.device_id, config);
+            if outgoing.send(notification).await.is_err() {
+                log::error!("Failed to notify server of new state, transport has stopped");
+            }
+        }
+    }
+}
+
+// keeps the cached state in line with what the physical remotes send the fans
+async fn observe_task(
+    app_state: &'static AppState,
+    config: &'static Config,
+    mut packets: mpsc::UnboundedReceiver<PacketData>,
+    outgoing: mpsc::Sender<ServerBoundSimpleMessage>,
+) {
+    while let Some(packet) = packets.recv().await {
+        for (&device_id, fan) in &app_state.fans {
+            let mut fan_state = fan.state.lock().await;
+            let mut changed = fan_state.observe(&packet, fan.config);
+            match &fan.confirmed {
+                Some(confirmed) => {
+                    let mut confirmed = confirmed.lock().await;
+                    changed = confirmed.observe(&packet, fan.config);
+                    if changed {
+                        fan.publish(&confirmed);
+                    }
+                }
+                None if changed => fan.publish(&fan_state),
+                None => {}
+            }
+            drop(fan_state);
+            if !changed {
+                continue;
+            }
+
+            log::info!("fan {device_id:?} was changed by something else, updating server");
+            let notification = fan.state_notification(device_id, config);
+            if outgoing.send(notification).await.is_err() {
+                log::error!("Failed to notify server of new state, transport has stopped");
+            }
+        }
+    }
+}
+
+fn keepalive_interval(fan_config: &FanConfig) -> Option<Duration> {
+    (fan_config.keepalive_interval_secs != 0)
+        .then(|| Duration::from_secs(fan_config.keepalive_interval_secs))
+}
+
+fn resync_interval(fan_config: &FanConfig) -> Option<Duration> {
+    fan_config.resync_interval_secs.map(Duration::from_secs)
+}
+
+// Sometimes the fan ignores commands when it has not received one for a while.
+// I have not found anything documenting this, but sending a 'keepalive' seems to work. 🤷‍♂️
+async fn keepalive_task(fan: &'static Fan, config: &'static Config) {
+    let jitter = Duration::from_secs(fan.config.keepalive_jitter_secs);
+    let mut interval_rx = fan.keepalive_interval.subscribe();
+    let mut restarted = true;
+
+    loop {
+        let interval = *interval_rx.borrow_and_update();
+        let Some(interval) = interval else {
+            if interval_rx.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+
+        // start at a random point in the interval so controllers that booted together
+        // don't all keepalive at once
+        let delay = match mem::take(&mut restarted) {
+            true => interval.mul_f64(rand::random()),
+            false => interval + jitter.mul_f64(rand::random()),
+        };
+        tokio::select! {
+            _ = sleep(delay) => {}
+            changed = interval_rx.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                restarted = true;
+                continue;
+            }
+        }
+
+        let mut fan_state = fan.state.lock().await;
+        let result = send_keepalive_to_fan(&mut fan_state, fan.config, config, fan.ble_adapter)
+            .await
+            .map_err(anyhow::Error::from);
+        fan.record_send_result(&result);
+
+        if let Err(err) = result {
+            log::error!("{:?}", err.context("Failed to send keepalive to fan"));
+        }
+    }
+}
+
+async fn resync_task(fan: &'static Fan, config: &'static Config) {
+    let mut interval_rx = fan.resync_interval.subscribe();
+
+    loop {
+        let interval = *interval_rx.borrow_and_update();
+        let Some(interval) = interval else {
+            if interval_rx.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+
+        // a new interval starts counting from the reload
+        tokio::select! {
+            _ = sleep(interval) => resync(fan, config).await,
+            changed = interval_rx.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn resync(fan: &Fan, config: &Config) {
+    let mut fan_state = fan.state.lock().await;
+    let result = resync_fan(&mut fan_state, fan.config, config, fan.ble_adapter)
+        .await
+        .map_err(anyhow::Error::from);
+    fan.record_send_result(&result);
+
+    if let Err(err) = result {
+        log::error!("{:?}", err.context("Failed to resync fan"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Level;
+    use tracing_subscriber::{Registry, layer::SubscriberExt};
+
+    #[test]
+    fn log_filter_honors_module_directives() {
+        let filter = log_filter("info,devicectrl_fan_controller::ble=debug").unwrap();
+
+        tracing::subscriber::with_default(Registry::default().with(filter), || {
+            assert!(tracing::enabled!(
+                target: "devicectrl_fan_controller::ble",
+                Level::DEBUG
+            ));
+            assert!(tracing::enabled!(
+                target: "devicectrl_fan_controller::ble::scan",
+                Level::DEBUG
+            ));
+            assert!(!tracing::enabled!(
+                target: "devicectrl_fan_controller::fan",
+                Level::DEBUG
+            ));
+            assert!(tracing::enabled!(
+                target: "devicectrl_fan_controller::fan",
+                Level::INFO
+            ));
+        });
+    }
+
+    #[test]
+    fn log_filter_defaults_to_info() {
+        let filter = log_filter("").unwrap();
+
+        tracing::subscriber::with_default(Registry::default().with(filter), || {
+            assert!(tracing::enabled!(target: "devicectrl_fan_controller::ble", Level::INFO));
+            assert!(!tracing::enabled!(
+                target: "devicectrl_fan_controller::ble",
+                Level::DEBUG
+            ));
+        });
+    }
 }
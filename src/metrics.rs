@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+use devicectrl_common::DeviceId;
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+use crate::fan::CachedFanState;
+
+pub static PACKETS_SENT: AtomicU64 = AtomicU64::new(0);
+pub static BLE_SEND_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static SERVER_CONNECTS: AtomicU64 = AtomicU64::new(0);
+pub static LAST_COMMAND_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+// how long advertising took, retries included, so a slow adapter shows up before it fails
+pub static LAST_SEND_DURATION_MS: AtomicU64 = AtomicU64::new(0);
+pub static SEND_DURATION_MS: AtomicU64 = AtomicU64::new(0);
+pub static SEND_RETRIES: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_command() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    LAST_COMMAND_TIMESTAMP.store(now, Ordering::Relaxed);
+}
+
+pub struct FanHandle {
+    pub device_id: DeviceId,
+    pub state: &'static Mutex<CachedFanState>,
+}
+
+type Fans = Vec<FanHandle>;
+
+// Serves Prometheus style metrics to anything that connects. This is not meant to be a
+// real HTTP server, every request gets the metrics except for /sent, which lists the
+// recently sent packets.
+pub async fn serve(addr: SocketAddr, fans: Fans) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind metrics server to {addr}"))?;
+    let fans: &'static Fans = Box::leak(Box::new(fans));
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, fans).await {
+                log::debug!("{:?}", err.context("failed to answer metrics request"));
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, fans: &Fans) -> Result<()> {
+    // only the path matters, but the request has to be read before answering anyway
+    let mut request = [0u8; 1024];
+    let len = stream.read(&mut request).await?;
+    let mut request_line = str::from_utf8(&request[..len])
+        .unwrap_or_default()
+        .split_whitespace();
+    let path = request_line.nth(1);
+
+    let (content_type, body) = match path {
+        Some("/sent") => ("text/plain; charset=utf-8", render_sent(fans).await),
+        _ => ("text/plain; version=0.0.4", render(fans).await),
+    };
+
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: {content_type}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+async fn render(fans: &Fans) -> String {
+    let mut out = String::new();
+
+    let counters = [
+        (
+            "fan_controller_packets_sent_total",
+            "counter",
+            &PACKETS_SENT,
+        ),
+        (
+            "fan_controller_ble_send_errors_total",
+            "counter",
+            &BLE_SEND_ERRORS,
+        ),
+        (
+            "fan_controller_server_connects_total",
+            "counter",
+            &SERVER_CONNECTS,
+        ),
+        (
+            "fan_controller_last_command_timestamp_seconds",
+            "gauge",
+            &LAST_COMMAND_TIMESTAMP,
+        ),
+        (
+            "fan_controller_last_send_duration_milliseconds",
+            "gauge",
+            &LAST_SEND_DURATION_MS,
+        ),
+        (
+            "fan_controller_send_duration_milliseconds_total",
+            "counter",
+            &SEND_DURATION_MS,
+        ),
+        (
+            "fan_controller_send_retries_total",
+            "counter",
+            &SEND_RETRIES,
+        ),
+    ];
+    for (name, kind, value) in counters {
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+    }
+
+    let mut brightness = String::new();
+    let mut speed = String::new();
+    for fan in fans {
+        let label = format!("{:?}", fan.device_id)
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+        let state = fan.state.lock().await;
+
+        // unknown values are left out rather than reported as zero
+        if let Some(value) = state.brightness {
+            let _ = writeln!(
+                brightness,
+                "fan_controller_brightness{{fan=\"{label}\"}} {value}"
+            );
+        }
+        if let Some(value) = state.speed {
+            let _ = writeln!(speed, "fan_controller_fan_speed{{fan=\"{label}\"}} {value}");
+        }
+    }
+
+    let _ = writeln!(out, "# TYPE fan_controller_brightness gauge");
+    out.push_str(&brightness);
+    let _ = writeln!(out, "# TYPE fan_controller_fan_speed gauge");
+    out.push_str(&speed);
+
+    out
+}
+
+// one line per packet, oldest first
+async fn render_sent(fans: &Fans) -> String {
+    let mut out = String::new();
+
+    for fan in fans {
+        let device_id = fan.device_id;
+        let state = fan.state.lock().await;
+
+        for packet in &state.sent_log {
+            let timestamp = packet
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let [arg0, arg1, arg2] = packet.args;
+
+            let _ = writeln!(
+                out,
+                "{device_id:?} {}.{:03} uid={:#x} tx_count={} cmd={:#04x} args={arg0:02x} {arg1:02x} {arg2:02x} {}",
+                timestamp.as_secs(),
+                timestamp.subsec_millis(),
+                packet.uid,
+                packet.tx_count,
+                packet.cmd,
+                match packet.sent {
+                    true => "sent",
+                    false => "failed",
+                }
+            );
+        }
+    }
+
+    out
+}